@@ -0,0 +1,41 @@
+//! Replay the allocation sequence decoded from the fuzzer's input on every allocator.
+
+#![no_main]
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+use clacos_alloc::{
+    buddy::BuddyAllocator, bump::BumpAllocator, fixed_size_block::FixedSizeBlockAllocator,
+    linked_list::LinkedListAllocator, HeapBackend,
+};
+use common::{ops_from_bytes, Arena, Op};
+use libfuzzer_sys::fuzz_target;
+
+const ARENA_SIZE: usize = 256 * 1024;
+
+/// Replay `ops` on `allocator` and check that its free memory is fully coalesced afterwards
+fn check_coalesced<A: HeapBackend>(allocator: A, ops: &[Op])
+where
+    clacos_alloc::Locked<A>: std::alloc::GlobalAlloc,
+{
+    let arena = Arena::new(allocator, ARENA_SIZE);
+    let initial = arena.stats();
+    arena.replay(ops);
+    let after = arena.stats();
+    assert_eq!(after.free, initial.free);
+    assert_eq!(after.free_regions, initial.free_regions);
+    assert_eq!(after.live_allocations(), 0);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let ops = ops_from_bytes(data);
+    check_coalesced(LinkedListAllocator::new(), &ops);
+    check_coalesced(BuddyAllocator::new(), &ops);
+    check_coalesced(BumpAllocator::new(), &ops);
+
+    // blocks are never given back to the fallback allocator, so only check the allocations
+    let arena = Arena::new(FixedSizeBlockAllocator::new(), ARENA_SIZE);
+    arena.replay(&ops);
+    assert_eq!(arena.stats().live_allocations(), 0);
+});
@@ -0,0 +1,420 @@
+//! A heap memory allocator that hands out blocks whose sizes are powers of two, splitting larger
+//! blocks in two halves ("buddies") when no block of the right size is free, and merging buddies
+//! back together when both are free again.
+//!
+//! The memory given to the allocator is organized in zones. Each zone is cut in the largest
+//! possible blocks and keeps a bitmap telling, for every block order, which blocks are currently
+//! free. Free blocks themselves are kept in one doubly linked list per order. Together, these
+//! make both allocation (pop and split) and deallocation (check buddy and merge) take a number of
+//! steps bounded by the number of orders, whatever the state of the heap.
+//!
+//! Main drawbacks:
+//!
+//! - every allocation is rounded up to the next power of two, which can waste almost half of the
+//!   allocated block.
+//! - alignments larger than `ZONE_ALIGN` can not be honoured.
+
+use core::{mem, ptr};
+
+use alloc::alloc::{GlobalAlloc, Layout};
+
+use super::{fast_align_up, HeapBackend, HeapStats, Locked};
+
+/// Size of the smallest block (of order 0), which must be able to hold a `FreeBlock`.
+const MIN_BLOCK_SIZE: usize = 16;
+
+/// Number of block orders, blocks of order `k` being `MIN_BLOCK_SIZE << k` bytes large (so up to
+/// 128 MiB here).
+const ORDER_COUNT: usize = 24;
+
+/// Alignment of the start of every zone.
+///
+/// Blocks are aligned on their size relative to the start of their zone, so this is also the
+/// largest alignment the allocator can guarantee.
+const ZONE_ALIGN: usize = 4096;
+
+/// Size of a block of the given order
+const fn block_size(order: usize) -> usize {
+    MIN_BLOCK_SIZE << order
+}
+
+/// The metadata stored at the start of every *free* block, linking it in the free list of its
+/// order.
+///
+/// As with `linked_list::ListNode`, allocated blocks need no metadata since the deallocation
+/// function is given the layout, from which the order can be computed again.
+struct FreeBlock {
+    prev: *mut FreeBlock,
+    next: *mut FreeBlock,
+}
+
+/// One doubly linked list of free blocks per order.
+struct FreeLists {
+    heads: [*mut FreeBlock; ORDER_COUNT],
+}
+
+impl FreeLists {
+    const fn new() -> Self {
+        FreeLists {
+            heads: [ptr::null_mut(); ORDER_COUNT],
+        }
+    }
+
+    /// Push the block at `addr` on the list of the given order.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to an unused block of the given order.
+    unsafe fn push(&mut self, order: usize, addr: usize) {
+        let node = addr as *mut FreeBlock;
+        let head = self.heads[order];
+        unsafe {
+            node.write(FreeBlock {
+                prev: ptr::null_mut(),
+                next: head,
+            });
+            if !head.is_null() {
+                (*head).prev = node;
+            }
+        }
+        self.heads[order] = node;
+    }
+
+    /// Pop a block from the list of the given order, returning its address.
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let head = self.heads[order];
+        if head.is_null() {
+            return None;
+        }
+        unsafe { self.remove(order, head as usize) };
+        Some(head as usize)
+    }
+
+    /// Unlink the block at `addr` from the list of the given order.
+    ///
+    /// # Safety
+    ///
+    /// The block at `addr` must currently be in the list of the given order.
+    unsafe fn remove(&mut self, order: usize, addr: usize) {
+        let node = addr as *mut FreeBlock;
+        unsafe {
+            let FreeBlock { prev, next } = node.read();
+            if prev.is_null() {
+                self.heads[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+/// A contiguous memory area split in blocks, stored right after its last block.
+///
+/// The zone is followed by its bitmap, which holds one bit per block of each order telling if
+/// that exact block is in a free list (a free block's sub-blocks are *not* marked as free).
+struct Zone {
+    /// Address of the first block, aligned to `ZONE_ALIGN`
+    start: usize,
+    /// Number of order 0 blocks covered by the zone
+    block_count: usize,
+    /// Index in the bitmap of the first bit of each order
+    bitmap_offsets: [usize; ORDER_COUNT],
+    next: Option<&'static mut Zone>,
+}
+
+impl Zone {
+    /// Number of bits needed in the bitmap of a zone covering `block_count` order 0 blocks.
+    fn bitmap_bits(block_count: usize) -> usize {
+        (0..ORDER_COUNT).map(|order| block_count >> order).sum()
+    }
+
+    fn bitmap(&self) -> *mut u8 {
+        (self as *const Self as usize + mem::size_of::<Self>()) as *mut u8
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start + self.block_count * MIN_BLOCK_SIZE
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end_addr()
+    }
+
+    /// Number of blocks of the given order that fit in the zone
+    fn blocks_of_order(&self, order: usize) -> usize {
+        self.block_count >> order
+    }
+
+    fn block_index(&self, order: usize, addr: usize) -> usize {
+        (addr - self.start) / block_size(order)
+    }
+
+    /// Return the address of the buddy of the block at `addr`, if it exists in this zone.
+    fn buddy(&self, order: usize, addr: usize) -> Option<usize> {
+        let buddy_index = self.block_index(order, addr) ^ 1;
+        if buddy_index < self.blocks_of_order(order) {
+            Some(self.start + buddy_index * block_size(order))
+        } else {
+            None
+        }
+    }
+
+    fn is_free(&self, order: usize, addr: usize) -> bool {
+        let bit = self.bitmap_offsets[order] + self.block_index(order, addr);
+        let byte = unsafe { self.bitmap().add(bit / 8).read() };
+        byte & (1 << (bit % 8)) != 0
+    }
+
+    fn set_free(&self, order: usize, addr: usize, free: bool) {
+        let bit = self.bitmap_offsets[order] + self.block_index(order, addr);
+        unsafe {
+            let byte = self.bitmap().add(bit / 8);
+            if free {
+                byte.write(byte.read() | (1 << (bit % 8)));
+            } else {
+                byte.write(byte.read() & !(1 << (bit % 8)));
+            }
+        }
+    }
+}
+
+pub struct BuddyAllocator {
+    free_lists: FreeLists,
+    zones: Option<&'static mut Zone>,
+    alloc_count: usize,
+    dealloc_count: usize,
+}
+
+// The raw pointers in the free lists only ever point to memory owned by the allocator itself, so
+// moving it to another thread is fine.
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    /// Create a new BuddyAllocator initialized as empty with no backing heap area
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            free_lists: FreeLists::new(),
+            zones: None,
+            alloc_count: 0,
+            dealloc_count: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the given heap bounds are
+    /// valid and that the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.add_free_region(heap_start, heap_size);
+        }
+    }
+
+    /// Make the given memory region a new zone and add its blocks to the free lists.
+    ///
+    /// The zone metadata is stored at the end of the region, so slightly less than `size` bytes
+    /// end up available for allocations. Regions too small to hold any block are ignored.
+    ///
+    /// # Safety
+    ///
+    /// `addr` and `size` must describe a valid memory area that is readable, writeable, not
+    /// currently in use, and not already handed to this allocator.
+    pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        let start = fast_align_up(addr, ZONE_ALIGN);
+        let end = addr.saturating_add(size);
+        if start >= end {
+            return;
+        }
+
+        // Every order 0 block costs MIN_BLOCK_SIZE bytes plus at most two bits of bitmap (one at
+        // order 0, one half at order 1, one quarter at order 2...), use that as a first estimate
+        // of the number of blocks that fit and decrease it until the metadata fits too.
+        let overhead = mem::size_of::<Zone>() + ORDER_COUNT;
+        let mut block_count = (end - start).saturating_sub(overhead) * 4 / (MIN_BLOCK_SIZE * 4 + 1);
+        let zone_addr = loop {
+            if block_count == 0 {
+                return;
+            }
+            // the end of the blocks is aligned to MIN_BLOCK_SIZE, which is enough for a Zone
+            let zone_addr = start + block_count * MIN_BLOCK_SIZE;
+            let bitmap_size = (Zone::bitmap_bits(block_count) + 7) / 8;
+            if zone_addr + mem::size_of::<Zone>() + bitmap_size <= end {
+                break zone_addr;
+            }
+            block_count -= 1;
+        };
+
+        let mut bitmap_offsets = [0; ORDER_COUNT];
+        for order in 1..ORDER_COUNT {
+            bitmap_offsets[order] = bitmap_offsets[order - 1] + (block_count >> (order - 1));
+        }
+        let zone = Zone {
+            start,
+            block_count,
+            bitmap_offsets,
+            next: self.zones.take(),
+        };
+        let zone_ptr = zone_addr as *mut Zone;
+        let zone = unsafe {
+            zone_ptr.write(zone);
+            let zone = &mut *zone_ptr;
+            zone.bitmap()
+                .write_bytes(0, (Zone::bitmap_bits(block_count) + 7) / 8);
+            zone
+        };
+
+        // Cut the zone in the largest possible blocks, each aligned on its size relative to the
+        // start of the zone.
+        let mut index = 0;
+        while index < block_count {
+            let mut order = ORDER_COUNT - 1;
+            while index % (1 << order) != 0 || index + (1 << order) > block_count {
+                order -= 1;
+            }
+            let block = start + index * MIN_BLOCK_SIZE;
+            unsafe { self.free_lists.push(order, block) };
+            zone.set_free(order, block, true);
+            index += 1 << order;
+        }
+
+        self.zones = Some(zone);
+    }
+
+    /// Return the zone containing `addr`
+    fn zone_containing(zones: Option<&Zone>, addr: usize) -> Option<&Zone> {
+        let mut zone = zones;
+        while let Some(z) = zone {
+            if z.contains(addr) {
+                return Some(z);
+            }
+            zone = z.next.as_deref();
+        }
+        None
+    }
+
+    /// Return the order of the smallest block suitable for the given layout, or None if the
+    /// layout is too large for any block.
+    fn order_for(layout: Layout) -> Option<usize> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(MIN_BLOCK_SIZE)
+            .checked_next_power_of_two()?;
+        let order = (size.trailing_zeros() - MIN_BLOCK_SIZE.trailing_zeros()) as usize;
+        (order < ORDER_COUNT).then_some(order)
+    }
+
+    /// Allocate a block suitable for the given layout.
+    ///
+    /// Return a null pointer if no free block is large enough (out of memory).
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let order = match Self::order_for(layout) {
+            Some(order) if layout.align() <= ZONE_ALIGN => order,
+            _ => return ptr::null_mut(),
+        };
+
+        // find the smallest free block that is large enough
+        let (mut block_order, block) =
+            match (order..ORDER_COUNT).find_map(|o| self.free_lists.pop(o).map(|b| (o, b))) {
+                Some(found) => found,
+                None => return ptr::null_mut(), // out of memory
+            };
+        let zone = Self::zone_containing(self.zones.as_deref(), block)
+            .expect("free block outside of any zone");
+        zone.set_free(block_order, block, false);
+
+        // split it until it has the right size, freeing the upper half each time
+        while block_order > order {
+            block_order -= 1;
+            let upper_half = block + block_size(block_order);
+            unsafe { self.free_lists.push(block_order, upper_half) };
+            zone.set_free(block_order, upper_half, true);
+        }
+
+        self.alloc_count += 1;
+        block as *mut u8
+    }
+
+    /// Give the block at `ptr` back to the allocator, merging it with its buddy as long as
+    /// possible.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` on this same allocator with the same `layout`,
+    /// and must not be used anymore.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut order = Self::order_for(layout).expect("deallocating an impossible layout");
+        let mut block = ptr as usize;
+        let zone = Self::zone_containing(self.zones.as_deref(), block)
+            .expect("deallocating memory outside of any zone");
+        self.dealloc_count += 1;
+
+        while order < ORDER_COUNT - 1 {
+            match zone.buddy(order, block) {
+                Some(buddy) if zone.is_free(order, buddy) => {
+                    unsafe { self.free_lists.remove(order, buddy) };
+                    zone.set_free(order, buddy, false);
+                    block = block.min(buddy);
+                    order += 1;
+                }
+                _ => break,
+            }
+        }
+
+        unsafe { self.free_lists.push(order, block) };
+        zone.set_free(order, block, true);
+    }
+}
+
+impl HeapBackend for BuddyAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { BuddyAllocator::init(self, heap_start, heap_size) }
+    }
+
+    /// Every new region becomes a new zone, whose blocks are never merged with those of other
+    /// zones.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        unsafe { BuddyAllocator::add_free_region(self, addr, size) }
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            allocations: self.alloc_count,
+            deallocations: self.dealloc_count,
+            ..HeapStats::default()
+        };
+
+        let mut zone = self.zones.as_deref();
+        while let Some(z) = zone {
+            stats.used += z.end_addr() - z.start;
+            zone = z.next.as_deref();
+        }
+
+        for (order, &head) in self.free_lists.heads.iter().enumerate() {
+            let mut block = head;
+            while !block.is_null() {
+                stats.free += block_size(order);
+                stats.largest_free_block = block_size(order);
+                stats.free_regions += 1;
+                block = unsafe { (*block).next };
+            }
+        }
+        stats.used -= stats.free;
+        stats
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().dealloc(ptr, layout) }
+    }
+}
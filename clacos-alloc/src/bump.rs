@@ -10,13 +10,15 @@ use core::ptr;
 
 use alloc::alloc::{GlobalAlloc, Layout};
 
-use super::{Locked, fast_align_up};
+use super::{HeapBackend, HeapStats, Locked, fast_align_up};
 
 pub struct BumpAllocator {
     heap_start: usize,
     heap_end: usize,
     next: usize,
     allocations: usize,
+    alloc_count: usize,
+    dealloc_count: usize,
 }
 
 impl BumpAllocator {
@@ -26,6 +28,8 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            alloc_count: 0,
+            dealloc_count: 0,
         }
     }
 
@@ -42,6 +46,32 @@ impl BumpAllocator {
     }
 }
 
+impl HeapBackend for BumpAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { BumpAllocator::init(self, heap_start, heap_size) }
+    }
+
+    /// Only regions starting right at the end of the heap can be used, by making the heap larger.
+    /// Other regions are ignored.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        if addr == self.heap_end {
+            self.heap_end += size;
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        let free = self.heap_end - self.next;
+        HeapStats {
+            used: self.next - self.heap_start,
+            free,
+            largest_free_block: free,
+            free_regions: (free > 0) as usize,
+            allocations: self.alloc_count,
+            deallocations: self.dealloc_count,
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut bump = self.lock();
@@ -60,6 +90,7 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.alloc_count += 1;
             alloc_start as *mut u8
         }
     }
@@ -68,6 +99,7 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         let mut bump = self.lock();
 
         bump.allocations -= 1;
+        bump.dealloc_count += 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
@@ -0,0 +1,208 @@
+//! A wrapper around any other allocator that helps catching heap corruption bugs, at the cost of
+//! memory and speed.
+//!
+//! Every allocation is preceded by a header recording its state and layout, and surrounded by red
+//! zones filled with a known byte. Deallocations check them and panic with a description of the
+//! problem on:
+//!
+//! - a double free,
+//! - a layout that doesn't match the one given at allocation time,
+//! - an overwritten red zone (a write just before or just after the allocated memory).
+//!
+//! Freed memory is filled with a poison byte and kept in quarantine for a while before being
+//! given back to the wrapped allocator. This keeps the header of recently freed memory intact so
+//! that double frees are reliably detected, and writes after free are noticed when the memory
+//! leaves the quarantine.
+
+use core::{mem, slice};
+
+use alloc::alloc::{GlobalAlloc, Layout};
+
+use super::fast_align_up;
+
+/// Size of the red zones placed before and after every allocation
+const RED_ZONE_SIZE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+
+/// Byte written over freed memory
+const POISON_BYTE: u8 = 0x6b;
+
+/// Number of freed allocations kept before being given back to the wrapped allocator
+const QUARANTINE_SIZE: usize = 64;
+
+const ALLOCATED_MAGIC: usize = 0xa110_ca7e_a110_ca7e;
+const FREED_MAGIC: usize = 0xdead_beef_dead_beef;
+
+/// The metadata stored right before the first red zone of every allocation.
+///
+/// Memory layout of an allocation (`ptr` being the pointer handed to the caller):
+///
+/// ```text
+/// | padding | Header | red zone | caller's data | red zone |
+///                               ^ptr
+/// ```
+#[repr(C)]
+struct Header {
+    magic: usize,
+    size: usize,
+    align: usize,
+}
+
+/// The last freed allocations, stored as the pointers handed to the caller (0 being an empty
+/// slot).
+struct Quarantine {
+    entries: [usize; QUARANTINE_SIZE],
+    next: usize,
+}
+
+impl Quarantine {
+    /// Put `ptr` in quarantine, returning the oldest pointer if the quarantine was full.
+    fn push(&mut self, ptr: usize) -> Option<usize> {
+        let evicted = mem::replace(&mut self.entries[self.next], ptr);
+        self.next = (self.next + 1) % QUARANTINE_SIZE;
+        (evicted != 0).then_some(evicted)
+    }
+}
+
+pub struct DebugAllocator<G> {
+    inner: G,
+    quarantine: spin::Mutex<Quarantine>,
+}
+
+impl<G> DebugAllocator<G> {
+    pub const fn new(inner: G) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: spin::Mutex::new(Quarantine {
+                entries: [0; QUARANTINE_SIZE],
+                next: 0,
+            }),
+        }
+    }
+
+    /// Return the wrapped allocator
+    pub fn inner(&self) -> &G {
+        &self.inner
+    }
+
+    /// Return the layout to request from the wrapped allocator for the given layout, and the
+    /// offset of the caller's data in the resulting block.
+    fn inner_layout(layout: Layout) -> (Layout, usize) {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let offset = fast_align_up(mem::size_of::<Header>() + RED_ZONE_SIZE, align);
+        let size = offset + layout.size() + RED_ZONE_SIZE;
+        let inner_layout = Layout::from_size_align(size, align).expect("adjusting layout failed");
+        (inner_layout, offset)
+    }
+
+    /// Return the address of the header of the allocation at `ptr`
+    fn header(ptr: *mut u8) -> *mut Header {
+        ptr.wrapping_sub(RED_ZONE_SIZE + mem::size_of::<Header>()) as *mut Header
+    }
+
+    /// Panic if one of the red zones of the `size` bytes allocation at `ptr` was overwritten.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` for an allocation of `size` bytes.
+    unsafe fn check_red_zones(ptr: *mut u8, size: usize) {
+        let (before, after) = unsafe {
+            (
+                slice::from_raw_parts(ptr.sub(RED_ZONE_SIZE), RED_ZONE_SIZE),
+                slice::from_raw_parts(ptr.add(size), RED_ZONE_SIZE),
+            )
+        };
+        if before.iter().any(|&b| b != RED_ZONE_BYTE) {
+            panic!(
+                "heap corruption: memory right before the {} bytes at {:p} was overwritten: {:x?}",
+                size, ptr, before
+            );
+        }
+        if after.iter().any(|&b| b != RED_ZONE_BYTE) {
+            panic!(
+                "heap corruption: memory right after the {} bytes at {:p} was overwritten: {:x?}",
+                size, ptr, after
+            );
+        }
+    }
+}
+
+impl<G: GlobalAlloc> DebugAllocator<G> {
+    /// Give an allocation leaving the quarantine back to the wrapped allocator, after checking
+    /// that it wasn't written to since it was freed.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from the quarantine.
+    unsafe fn release(&self, ptr: *mut u8) {
+        let header = unsafe { &*Self::header(ptr) };
+        let size = header.size;
+        let layout = Layout::from_size_align(size, header.align).expect("corrupted header");
+
+        let data = unsafe { slice::from_raw_parts(ptr, size) };
+        if let Some(offset) = data.iter().position(|&b| b != POISON_BYTE) {
+            panic!(
+                "heap corruption: the {} bytes at {:p} were written to after being freed \
+                 (first at offset {})",
+                size, ptr, offset
+            );
+        }
+        unsafe { Self::check_red_zones(ptr, size) };
+
+        let (inner_layout, offset) = Self::inner_layout(layout);
+        unsafe { self.inner.dealloc(ptr.sub(offset), inner_layout) }
+    }
+}
+
+unsafe impl<G: GlobalAlloc> GlobalAlloc for DebugAllocator<G> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (inner_layout, offset) = Self::inner_layout(layout);
+        let block = unsafe { self.inner.alloc(inner_layout) };
+        if block.is_null() {
+            return block;
+        }
+
+        unsafe {
+            let ptr = block.add(offset);
+            Self::header(ptr).write(Header {
+                magic: ALLOCATED_MAGIC,
+                size: layout.size(),
+                align: layout.align(),
+            });
+            ptr.sub(RED_ZONE_SIZE)
+                .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+            ptr.add(layout.size())
+                .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+            ptr
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = unsafe { &mut *Self::header(ptr) };
+        match header.magic {
+            ALLOCATED_MAGIC => {}
+            FREED_MAGIC => panic!("heap corruption: double free of {:p} ({:?})", ptr, layout),
+            _ => panic!(
+                "heap corruption: deallocating {:p}, which is not a live allocation or whose \
+                 header was overwritten",
+                ptr
+            ),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "heap corruption: deallocating {:p} with {:?}, but it was allocated with size {} \
+                 and align {}",
+                ptr, layout, header.size, header.align
+            );
+        }
+        unsafe { Self::check_red_zones(ptr, layout.size()) };
+
+        header.magic = FREED_MAGIC;
+        unsafe { ptr.write_bytes(POISON_BYTE, layout.size()) };
+
+        let evicted = self.quarantine.lock().push(ptr as usize);
+        if let Some(evicted) = evicted {
+            unsafe { self.release(evicted as *mut u8) };
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! A heap memory allocator that serves small allocations from per-size-class lists of fixed-size
+//! blocks, and defers to a `LinkedListAllocator` for everything else.
+//!
+//! Every allocation is rounded up to the smallest block size that can hold it. Freed blocks are
+//! pushed on the list of their size class and reused by the next allocation of the same class,
+//! so both operations are O(1) as long as the list is not empty.
+//!
+//! Main drawbacks:
+//!
+//! - memory is wasted by rounding every allocation up to its block size (up to half of the block
+//!   in the worst case).
+//! - blocks are never given back to the fallback allocator, so memory used once for small
+//!   allocations can not be reused for large ones later.
+
+use core::mem;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+
+use super::{linked_list::LinkedListAllocator, HeapBackend, HeapStats, Locked};
+
+/// The block sizes to use.
+///
+/// The sizes must each be a power of two because they are also used as the block alignment
+/// (alignments must always be powers of two). Allocations larger than the last size are served by
+/// the fallback allocator.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The metadata stored at the start of every *free* block.
+///
+/// Unlike `linked_list::ListNode`, there is no need to store the size of the block since it is
+/// given by the list the block belongs to. This is what allows blocks as small as 8 bytes.
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+    alloc_count: usize,
+    dealloc_count: usize,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Create a new FixedSizeBlockAllocator initialized as empty with no backing heap area
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+            alloc_count: 0,
+            dealloc_count: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the given heap bounds are
+    /// valid and that the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.fallback_allocator.init(heap_start, heap_size);
+        }
+    }
+
+    /// Allocate using the fallback allocator.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback_allocator.alloc(layout)
+    }
+
+    /// Choose an appropriate block size for the given layout.
+    ///
+    /// Return an index into the `BLOCK_SIZES` array, or None if the layout is too large to fit in
+    /// a block.
+    fn list_index(layout: &Layout) -> Option<usize> {
+        let required_block_size = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+    }
+}
+
+impl HeapBackend for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { FixedSizeBlockAllocator::init(self, heap_start, heap_size) }
+    }
+
+    /// New regions go to the fallback allocator, from which new blocks are taken.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        unsafe { HeapBackend::add_free_region(&mut self.fallback_allocator, addr, size) }
+    }
+
+    /// Free blocks waiting in the lists count as free memory (and as one free region each), even
+    /// though they can only be reused for allocations of their size class.
+    fn stats(&self) -> HeapStats {
+        let mut stats = self.fallback_allocator.stats();
+        for (head, &block_size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            // the fallback allocator may round the blocks up
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            let allocated_size = LinkedListAllocator::allocated_size(layout);
+            let mut block = head.as_deref();
+            while let Some(node) = block {
+                stats.free += allocated_size;
+                stats.used -= allocated_size;
+                stats.largest_free_block = stats.largest_free_block.max(block_size);
+                stats.free_regions += 1;
+                block = node.next.as_deref();
+            }
+        }
+        stats.allocations = self.alloc_count;
+        stats.deallocations = self.dealloc_count;
+        stats
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+
+        let ptr = match FixedSizeBlockAllocator::list_index(&layout) {
+            Some(index) => match allocator.list_heads[index].take() {
+                Some(node) => {
+                    // reuse a previously freed block of the right size
+                    allocator.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // no block of this size exists yet, allocate a new one from the fallback
+                    // allocator, using the block size as alignment so that it can be reused for
+                    // any layout of this size class
+                    let block_size = BLOCK_SIZES[index];
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    allocator.fallback_alloc(layout)
+                }
+            },
+            None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.alloc_count += 1;
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.dealloc_count += 1;
+
+        match FixedSizeBlockAllocator::list_index(&layout) {
+            Some(index) => {
+                // Put the block back at the head of its list.
+                // Make sure that the block has the size and alignment required for storing a
+                // node (the smallest block size is enough for a `ListNode`)
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                let new_node_ptr = ptr as *mut ListNode;
+                unsafe {
+                    new_node_ptr.write(new_node);
+                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                }
+            }
+            None => unsafe { allocator.fallback_allocator.dealloc(ptr, layout) },
+        }
+    }
+}
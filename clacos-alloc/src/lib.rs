@@ -0,0 +1,108 @@
+//! The heap allocators of the kernel.
+//!
+//! These don't depend on anything kernel specific, so this crate builds for the host too, which is
+//! how the allocators are tested (see `tests/`) and fuzzed (see `fuzz/`).
+
+#![no_std]
+// Force the use of `unsafe` blocks around unsafe operations even in `unsafe` functions
+#![deny(unsafe_op_in_unsafe_fn)]
+// Allow mutable references in const functions
+#![feature(const_mut_refs)]
+
+extern crate alloc;
+
+use alloc::alloc::Layout;
+
+pub mod buddy;
+pub mod bump;
+pub mod debug;
+pub mod fixed_size_block;
+pub mod linked_list;
+
+/// The operations a heap needs from its allocator backend, on top of `GlobalAlloc`.
+pub trait HeapBackend {
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the given heap bounds are valid and that the heap is
+    /// unused. This method must be called only once.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Hand a new memory region to the allocator.
+    ///
+    /// The kernel heap only ever adds regions right after the end of the previous one, some
+    /// backends rely on it.
+    ///
+    /// # Safety
+    ///
+    /// `addr` and `size` must describe a valid memory area that is readable, writeable, not
+    /// currently in use, and not already handed to this allocator.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize);
+
+    /// Try to resize the allocation at `ptr` to `new_size` bytes without moving it, and return
+    /// whether it did. When it didn't, the allocation is left untouched.
+    ///
+    /// The default implementation never resizes in place.
+    ///
+    /// # Safety
+    ///
+    /// Same as `GlobalAlloc::realloc`.
+    unsafe fn resize_in_place(&mut self, _ptr: *mut u8, _layout: Layout, _new_size: usize) -> bool {
+        false
+    }
+
+    /// Return a snapshot of the allocator's state.
+    ///
+    /// This may walk the allocator's whole metadata, so it is meant for diagnostics only.
+    fn stats(&self) -> HeapStats;
+}
+
+/// A snapshot of the state of a heap allocator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes currently allocated, including what the allocator wastes by rounding allocations up
+    pub used: usize,
+    /// Bytes available for new allocations
+    pub free: usize,
+    /// Size of the largest free block, above which allocations can only succeed by growing the
+    /// heap
+    pub largest_free_block: usize,
+    /// Number of distinct free regions, the higher the more fragmented the heap is
+    pub free_regions: usize,
+    /// Number of successful allocations since the allocator was created
+    pub allocations: usize,
+    /// Number of deallocations since the allocator was created
+    pub deallocations: usize,
+}
+
+impl HeapStats {
+    /// Number of allocations that were not deallocated yet
+    pub fn live_allocations(&self) -> usize {
+        self.allocations - self.deallocations
+    }
+}
+
+/// Wrapper type based on spin::Mutex to enable interior mutability
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// Align the given address upwards to a multiple of `align`.
+///
+/// Requires that `align` is a power of two
+fn fast_align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
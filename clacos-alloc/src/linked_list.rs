@@ -0,0 +1,589 @@
+//! A heap memory allocator that uses a linked list of unused memory region
+//!
+//! Main drawbacks:
+//!
+//! - slow allocation if the heap is very fragmented
+//! - free regions are merged with their neighbours when deallocated, but small long-lived
+//! allocations can still leave the heap fragmented in many regions, none of which are
+//! individually large enough to fulfill a caller's request even though there is enough memory
+//! available in total.
+//!
+//! The first drawback is inherent to the linked list design. The second one depends on where
+//! allocations are placed, which is configurable (see `FitPolicy`).
+
+use core::{cmp::Ordering, mem, ptr};
+
+use alloc::alloc::{GlobalAlloc, Layout};
+
+use super::{fast_align_up, HeapBackend, HeapStats, Locked};
+
+/// The metadata stored at the start of every *free* memory region.
+///
+/// The allocation process finds a suitable memory region and removes it from the list altogether,
+/// so these metadata are not needed anymore once the region is allocated and can be safely
+/// overwritten by the caller. This is possible because the deallocation function requires the
+/// caller to provide the size of the region, which means that we don't have to keep track of it
+/// ourselves for used regions, which means that we don't need *any* metadata at all for used
+/// regions. It does mean, though, that we need to ensure every allocated region is large enough to
+/// store a `ListNode`, because we will write a new one at its start once it gets deallocated (see
+/// `LinkedListAllocator::size_align()`).
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// How `LinkedListAllocator` chooses the free region to allocate from, when several are large
+/// enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// The first region of the list (the one with the lowest address). Fast, but small fragments
+    /// tend to pile up at the start of the list, making the following searches slower.
+    FirstFit,
+    /// The first region after the one the previous allocation was taken from, wrapping around at
+    /// the end of the list. Spreads the fragments over the whole heap.
+    NextFit,
+    /// The smallest region, which leaves the smallest fragment but walks the whole list unless a
+    /// region fits exactly.
+    BestFit,
+}
+
+pub struct LinkedListAllocator {
+    head: ListNode,
+    policy: FitPolicy,
+    /// Address of the free region after which `FitPolicy::NextFit` starts looking (0 for the head
+    /// of the list), which is kept in the list as regions are allocated and merged
+    rover: usize,
+    /// Total size of the memory regions handed to the allocator
+    heap_size: usize,
+    alloc_count: usize,
+    dealloc_count: usize,
+}
+
+impl LinkedListAllocator {
+    /// Create a new LinkedListAllocator initialized as empty with no backing heap area, using
+    /// first-fit placement
+    pub const fn new() -> LinkedListAllocator {
+        Self::with_policy(FitPolicy::FirstFit)
+    }
+
+    /// Create a new LinkedListAllocator initialized as empty with no backing heap area, using the
+    /// given placement policy
+    pub const fn with_policy(policy: FitPolicy) -> LinkedListAllocator {
+        Self {
+            head: ListNode::new(0),
+            policy,
+            rover: 0,
+            heap_size: 0,
+            alloc_count: 0,
+            dealloc_count: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the given heap bounds are
+    /// valid and that the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_size = heap_size;
+        unsafe {
+            self.add_free_region(heap_start, heap_size);
+        }
+    }
+
+    /// Return an iterator over the free regions, in address order, as `(start, size)` tuples.
+    pub fn free_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.regions()
+            .map(|region| (region.start_addr(), region.size))
+    }
+
+    /// Return an iterator over the nodes of the free regions, in address order.
+    fn regions(&self) -> impl Iterator<Item = &ListNode> + '_ {
+        let mut region = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let current = region?;
+            region = current.next.as_deref();
+            Some(current)
+        })
+    }
+
+    /// Return the placement policy of the allocator.
+    pub fn policy(&self) -> FitPolicy {
+        self.policy
+    }
+
+    /// Change the placement policy of the allocator, which applies to the next allocations.
+    pub fn set_policy(&mut self, policy: FitPolicy) {
+        self.policy = policy;
+    }
+
+    /// Add the given memory region to the list, keeping it sorted by start address and merging
+    /// with adjacent free regions.
+    ///
+    /// # Safety
+    ///
+    /// `addr` and `size` must describe a valid memory area that is readable, writeable, and not
+    /// currently in use. Using only information given by allocation functions from this module
+    /// should suffice.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // Ensure that the freed region is capable of holding a ListNode struct (check that `addr`
+        // is correctly aligned for `ListNode` and that size is large enough to store it).
+        // These are asserts because the given `addr` and `size` should have been computed by
+        // allocation functions from this same module, so these are only consistency checks
+        assert_eq!(fast_align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // Create a new node and prepare its address
+        let mut node = ListNode::new(size);
+        let node_ptr = addr as *mut ListNode;
+
+        // Find where to insert the node in the linked list.
+        // At each iteration we may have three references to three nodes, with the goal of changing
+        // the links from:
+        //      previous -> next
+        // to:
+        //      previous -> node -> next
+        // with addr_of!(previous) <= addr_of!(node) <= addr_of!(next)
+        // (not accounting for possible merges)
+        let head_addr = &self.head as *const ListNode as usize;
+        let mut previous = &mut self.head;
+        while let Some(ref mut next) = previous.next {
+            if *next as *const ListNode as usize > addr {
+                break;
+            }
+            previous = previous.next.as_mut().unwrap();
+        }
+
+        // See if `previous` and/or `previous.next` are adjacent to the new free region, so that we
+        // can merge with them.
+        let can_merge_right = {
+            if let Some(ref next) = previous.next {
+                let after_node_addr = fast_align_up(addr + size, mem::align_of::<ListNode>());
+                let next_addr = *next as *const ListNode as usize;
+                after_node_addr == next_addr
+            } else {
+                false
+            }
+        };
+        let can_merge_left = {
+            let previous_addr = previous as *const ListNode as usize;
+            let after_previous_addr =
+                fast_align_up(previous_addr + previous.size, mem::align_of::<ListNode>());
+            after_previous_addr == addr
+        };
+
+        // The region merged right disappears from the list, so the rover must move before it
+        let previous_addr = match previous as *const ListNode as usize {
+            addr if addr == head_addr => 0,
+            addr => addr,
+        };
+        let merged_right = match previous.next {
+            Some(ref next) if can_merge_right => Some(next.start_addr()),
+            _ => None,
+        };
+
+        // Merge where possible and rewrite all links but those pointing to *node_ptr (which exists
+        // only if we don't merge left, checked after)
+        if can_merge_left && can_merge_right {
+            // Both `previous` and `previous.next` are adjacent, merge with both.
+            let right = unsafe {
+                // XXX: `can_merge_right` ensures that `previous.next` is not None
+                previous.next.take().unwrap_unchecked()
+            };
+            previous.size = fast_align_up(
+                previous.size + node.size + right.size,
+                mem::align_of::<ListNode>(),
+            );
+            previous.next = right.next.take();
+        } else if can_merge_left {
+            // Only `previous` is adjacent, merge with it.
+            previous.size = fast_align_up(previous.size + node.size, mem::align_of::<ListNode>());
+        } else if can_merge_right {
+            // Only `previous.next` is adjacent, merge with it.
+            let right = unsafe {
+                // XXX: `can_merge_right` ensures that `previous.next` is not None
+                previous.next.take().unwrap_unchecked()
+            };
+            node.next = right.next.take();
+            node.size = fast_align_up(size + right.size, mem::align_of::<ListNode>());
+        } else {
+            // No merge possible
+            node.next = previous.next.take();
+        }
+
+        if !can_merge_left {
+            // We didn't merge with `previous` so we actually have to write a node at `*note_ptr`
+            // and make it the successor of `previous`.
+            unsafe {
+                node_ptr.write(node);
+                previous.next = Some(&mut *node_ptr)
+            }
+        }
+
+        if merged_right == Some(self.rover) {
+            self.rover = previous_addr;
+        }
+    }
+
+    /// Look for a free region compatible with the required size and alignment, according to the
+    /// placement policy, and remove it from the list.
+    ///
+    /// Return a tuple of the list node and the start address of the allocation
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let (previous, alloc_start) = match self.policy {
+            FitPolicy::FirstFit => self.find_first_fit(0, None, size, align),
+            FitPolicy::NextFit => {
+                let rover = self.rover;
+                match self.find_first_fit(rover, None, size, align) {
+                    Some(found) => Some(found),
+                    // wrap around, up to the rover
+                    None if rover != 0 => self.find_first_fit(0, Some(rover), size, align),
+                    None => None,
+                }
+            }
+            FitPolicy::BestFit => self.find_best_fit(size, align),
+        }?;
+
+        let region = self.unlink_after(previous);
+        self.rover = previous;
+        Some((region, alloc_start))
+    }
+
+    /// Look for the first free region compatible with the required size and alignment, among the
+    /// ones after the node at `start` (0 for the head of the list), and up to the one at `stop` if
+    /// any.
+    ///
+    /// Return a tuple of the address of the node before the region (0 for the head of the list)
+    /// and the start address of the allocation
+    fn find_first_fit(
+        &self,
+        start: usize,
+        stop: Option<usize>,
+        size: usize,
+        align: usize,
+    ) -> Option<(usize, usize)> {
+        let mut previous = start;
+        let mut region = unsafe { self.node(start) }.next.as_deref();
+        while let Some(current) = region {
+            if let Ok(alloc_start) = Self::alloc_from_region_possible(current, size, align) {
+                return Some((previous, alloc_start));
+            }
+            if stop == Some(current.start_addr()) {
+                return None;
+            }
+            previous = current.start_addr();
+            region = current.next.as_deref();
+        }
+
+        None
+    }
+
+    /// Look for the smallest free region compatible with the required size and alignment (the
+    /// first one of them if there are several).
+    ///
+    /// Return a tuple of the address of the node before the region (0 for the head of the list)
+    /// and the start address of the allocation
+    fn find_best_fit(&self, size: usize, align: usize) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize, usize)> = None;
+        let mut previous = 0;
+        for region in self.regions() {
+            if let Ok(alloc_start) = Self::alloc_from_region_possible(region, size, align) {
+                if best.map_or(true, |(_, _, best_size)| region.size < best_size) {
+                    best = Some((previous, alloc_start, region.size));
+                }
+                if region.size == size {
+                    // it can't get any better
+                    break;
+                }
+            }
+            previous = region.start_addr();
+        }
+
+        best.map(|(previous, alloc_start, _)| (previous, alloc_start))
+    }
+
+    /// Return the node at `addr`, or the head of the list for 0.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `addr` is 0 or the address
+    /// of a node of the list.
+    unsafe fn node(&self, addr: usize) -> &ListNode {
+        if addr == 0 {
+            &self.head
+        } else {
+            unsafe { &*(addr as *const ListNode) }
+        }
+    }
+
+    /// Remove the free region after the node at `previous` (0 for the head of the list) from the
+    /// list, and return it.
+    ///
+    /// `previous` must be 0 or the address of a node of the list, which isn't the last one.
+    fn unlink_after(&mut self, previous: usize) -> &'static mut ListNode {
+        let previous_node = if previous == 0 {
+            &mut self.head
+        } else {
+            unsafe { &mut *(previous as *mut ListNode) }
+        };
+        let region = previous_node.next.take().expect("no region to unlink");
+        previous_node.next = region.next.take();
+
+        // the rover must stay in the list
+        if self.rover == region.start_addr() {
+            self.rover = previous;
+        }
+        region
+    }
+
+    /// Remove the free region starting at `addr` from the list, if there is one.
+    fn take_region_at(&mut self, addr: usize) -> Option<&'static mut ListNode> {
+        // The list being sorted, we can stop at the first region after `addr`.
+        let mut previous = 0;
+        for region in self.regions() {
+            match region.start_addr().cmp(&addr) {
+                Ordering::Less => previous = region.start_addr(),
+                Ordering::Equal => break,
+                Ordering::Greater => return None,
+            }
+        }
+
+        match unsafe { self.node(previous) }.next {
+            Some(ref region) if region.start_addr() == addr => Some(self.unlink_after(previous)),
+            _ => None,
+        }
+    }
+
+    /// Check if the given memory region is suitable for an allocation of the given size and
+    /// alignment.
+    ///
+    /// On succes, return the alligned start address of the allocation, otherwise return Err(())
+    fn alloc_from_region_possible(
+        region: &ListNode,
+        size: usize,
+        align: usize,
+    ) -> Result<usize, ()> {
+        let mut alloc_start = fast_align_up(region.start_addr(), align);
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            // Same as the excess size below: the part of the region before the allocation will
+            // become a free region of its own, so move the allocation further to make room for a
+            // ListNode.
+            alloc_start = fast_align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            // region too small
+            return Err(());
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // The rest of the region (the part unused by the allocation) is too small to hold a
+            // ListNode. This is needed because, with an non-zero excess size, the region would be
+            // split in two by the allocation to form a new free region with the unused part, which
+            // needs to be handled by a ListNode.
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    /// Allocate a memory region suitable for the given layout.
+    ///
+    /// Return a null pointer if no free region is large enough (out of memory).
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
+            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let excess_size = region_end - alloc_end;
+            if excess_size > 0 {
+                unsafe {
+                    self.add_free_region(alloc_end, excess_size);
+                }
+            }
+            // give back the part of the region skipped to align the allocation, if any
+            let padding = alloc_start - region_start;
+            if padding > 0 {
+                unsafe {
+                    self.add_free_region(region_start, padding);
+                }
+            }
+            self.alloc_count += 1;
+            alloc_start as *mut u8
+        } else {
+            // no suitable memory region found (out of memory)
+            ptr::null_mut()
+        }
+    }
+
+    /// Give the memory region at `ptr` back to the free list.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` on this same allocator with the same `layout`,
+    /// and must not be used anymore.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.dealloc_count += 1;
+        unsafe { self.add_free_region(ptr as usize, size) }
+    }
+
+    /// Try to resize the allocation at `ptr` without moving it, and return whether it did.
+    ///
+    /// Growing takes the needed memory from the free region right after the allocation, if there
+    /// is one large enough. Shrinking gives the tail of the allocation back to the free list.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` on this same allocator with the same `layout`, and
+    /// `new_size` must be valid for the same alignment (see `GlobalAlloc::realloc`).
+    pub unsafe fn resize_in_place(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> bool {
+        let (size, _) = Self::size_align(layout);
+        let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => new_layout,
+            Err(_) => return false,
+        };
+        let (new_size, _) = Self::size_align(new_layout);
+        let addr = ptr as usize;
+
+        if new_size <= size {
+            // Shrink: the tail becomes a free region of its own, so like any excess size (see
+            // `alloc_from_region_possible`) it must be able to hold a ListNode.
+            let tail = size - new_size;
+            if tail > 0 && tail < mem::size_of::<ListNode>() {
+                return false;
+            }
+            if tail > 0 {
+                unsafe { self.add_free_region(addr + new_size, tail) };
+            }
+            return true;
+        }
+
+        // Grow: take the free region right after the allocation, if any
+        let next = match self.take_region_at(addr + size) {
+            Some(next) => next,
+            None => return false,
+        };
+        let (next_start, next_size) = (next.start_addr(), next.size);
+        let needed = new_size - size;
+        let excess_size = next_size.saturating_sub(needed);
+        if next_size < needed || (excess_size > 0 && excess_size < mem::size_of::<ListNode>()) {
+            // too small, or what's left can't hold a ListNode: give the region back untouched
+            unsafe { self.add_free_region(next_start, next_size) };
+            return false;
+        }
+        if excess_size > 0 {
+            unsafe { self.add_free_region(addr + new_size, excess_size) };
+        }
+        true
+    }
+
+    /// Return how many bytes an allocation of the given layout takes from the free regions, not
+    /// counting the padding needed to align it.
+    pub fn allocated_size(layout: Layout) -> usize {
+        Self::size_align(layout).0
+    }
+
+    /// Adjust the given layout so that the resulting allocated memory region is also suitable for
+    /// storing a `ListNode` (which it will once it is deallocated).
+    ///
+    /// Return the adjusted size and alignement as a (size, align) tuple.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            // pad the size to a multiple of the new alignment to make sure the following region's
+            // start address has the same alignment
+            .pad_to_align();
+
+        // increase the allocation size if needed so that it can at least hold a `ListNode`.
+        // Doing it after `pad_to_align` may give a size that is not a multiple of the layout's
+        // alignment (only when it is above `size_of::<ListNode>()`, and only for zero-sized
+        // layouts), but that is fine: what matters is that the following region's start stays
+        // aligned for a `ListNode`, and `size_of::<ListNode>()` is a multiple of its alignment.
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl HeapBackend for LinkedListAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { LinkedListAllocator::init(self, heap_start, heap_size) }
+    }
+
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        self.heap_size += size;
+        unsafe { LinkedListAllocator::add_free_region(self, addr, size) }
+    }
+
+    unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        unsafe { LinkedListAllocator::resize_in_place(self, ptr, layout, new_size) }
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            allocations: self.alloc_count,
+            deallocations: self.dealloc_count,
+            ..HeapStats::default()
+        };
+        for (_, size) in self.free_regions() {
+            stats.free += size;
+            stats.largest_free_block = stats.largest_free_block.max(size);
+            stats.free_regions += 1;
+        }
+        stats.used = self.heap_size - stats.free;
+        stats
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().dealloc(ptr, layout) }
+    }
+
+    /// Resize in place when possible, otherwise move the allocation like the default
+    /// implementation does.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if unsafe { self.lock().resize_in_place(ptr, layout, new_size) } {
+            return ptr;
+        }
+
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
@@ -0,0 +1,120 @@
+//! Property-based tests replaying random allocation sequences on every allocator.
+
+mod common;
+
+use std::panic::{self, AssertUnwindSafe};
+
+use clacos_alloc::{
+    buddy::BuddyAllocator,
+    bump::BumpAllocator,
+    debug::DebugAllocator,
+    fixed_size_block::FixedSizeBlockAllocator,
+    linked_list::{FitPolicy, LinkedListAllocator},
+    HeapBackend, HeapStats, Locked,
+};
+use common::{random_ops, Arena, Op, Rng, ARENA_ALIGN};
+
+const ARENA_SIZE: usize = 64 * 1024;
+
+/// Number of random sequences to try for each allocator
+const SEQUENCES: u64 = 500;
+
+/// Run `check` on many random operation sequences, reporting the seed of the sequence that
+/// failed, if any.
+fn for_each_sequence(check: impl Fn(&[Op])) {
+    for seed in 0..SEQUENCES {
+        let ops = random_ops(&mut Rng::new(seed), 300, ARENA_SIZE / 4);
+        if let Err(error) = panic::catch_unwind(AssertUnwindSafe(|| check(&ops))) {
+            eprintln!("failed with the sequence of seed {}: {:?}", seed, ops);
+            panic::resume_unwind(error);
+        }
+    }
+}
+
+/// Check that, with everything freed, the free memory is back in as few regions as it started in
+fn assert_fully_coalesced(initial: HeapStats, after: HeapStats) {
+    assert_eq!(after.free, initial.free);
+    assert_eq!(after.largest_free_block, initial.largest_free_block);
+    assert_eq!(after.free_regions, initial.free_regions);
+    assert_eq!(after.live_allocations(), 0);
+}
+
+#[test]
+fn linked_list() {
+    for_each_sequence(|ops| {
+        let arena = Arena::new(LinkedListAllocator::new(), ARENA_SIZE);
+        let initial = arena.stats();
+        arena.replay(ops);
+        assert_fully_coalesced(initial, arena.stats());
+    });
+}
+
+#[test]
+fn linked_list_next_fit() {
+    for_each_sequence(|ops| {
+        let allocator = LinkedListAllocator::with_policy(FitPolicy::NextFit);
+        let arena = Arena::new(allocator, ARENA_SIZE);
+        let initial = arena.stats();
+        arena.replay(ops);
+        assert_fully_coalesced(initial, arena.stats());
+    });
+}
+
+#[test]
+fn linked_list_best_fit() {
+    for_each_sequence(|ops| {
+        let allocator = LinkedListAllocator::with_policy(FitPolicy::BestFit);
+        let arena = Arena::new(allocator, ARENA_SIZE);
+        let initial = arena.stats();
+        arena.replay(ops);
+        assert_fully_coalesced(initial, arena.stats());
+    });
+}
+
+#[test]
+fn buddy() {
+    for_each_sequence(|ops| {
+        let arena = Arena::new(BuddyAllocator::new(), ARENA_SIZE);
+        let initial = arena.stats();
+        arena.replay(ops);
+        assert_fully_coalesced(initial, arena.stats());
+    });
+}
+
+#[test]
+fn bump() {
+    for_each_sequence(|ops| {
+        let arena = Arena::new(BumpAllocator::new(), ARENA_SIZE);
+        let initial = arena.stats();
+        arena.replay(ops);
+        assert_fully_coalesced(initial, arena.stats());
+    });
+}
+
+/// Blocks are never given back to the fallback allocator, so there is no coalescing to check
+#[test]
+fn fixed_size_block() {
+    for_each_sequence(|ops| {
+        let arena = Arena::new(FixedSizeBlockAllocator::new(), ARENA_SIZE);
+        arena.replay(ops);
+        let stats = arena.stats();
+        assert_eq!(stats.live_allocations(), 0);
+        // the free blocks count as free memory, whatever their size
+        assert_eq!(stats.used, 0);
+        assert_eq!(stats.free, ARENA_SIZE);
+    });
+}
+
+/// Correct allocation sequences must never trip the debug allocator's checks
+#[test]
+fn debug() {
+    for_each_sequence(|ops| {
+        let memory = vec![0u8; ARENA_SIZE + ARENA_ALIGN];
+        let start = (memory.as_ptr() as usize + ARENA_ALIGN - 1) & !(ARENA_ALIGN - 1);
+        let mut inner = LinkedListAllocator::new();
+        unsafe { HeapBackend::init(&mut inner, start, ARENA_SIZE) };
+        let allocator = DebugAllocator::new(Locked::new(inner));
+        let arena = Arena::from_parts(allocator, memory, start, ARENA_SIZE);
+        arena.replay(ops);
+    });
+}
@@ -0,0 +1,248 @@
+//! Helpers to run the allocators on the host, over memory taken from the host's own heap.
+//!
+//! Also used by the fuzz targets (see `fuzz/`).
+
+#![allow(dead_code)]
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use clacos_alloc::{HeapBackend, HeapStats, Locked};
+
+/// Alignment of the memory handed to the allocators, like the kernel heap which starts on a page
+pub const ARENA_ALIGN: usize = 4096;
+
+/// An allocator initialized over a memory area taken from a `Vec<u8>`.
+pub struct Arena<G> {
+    // declared first to make sure it's dropped before the memory it points to
+    pub allocator: G,
+    start: usize,
+    size: usize,
+    _memory: Vec<u8>,
+}
+
+impl<A: HeapBackend> Arena<Locked<A>> {
+    /// Initialize `allocator` over a fresh `size` bytes memory area.
+    pub fn new(mut allocator: A, size: usize) -> Self {
+        let memory = vec![0u8; size + ARENA_ALIGN];
+        let start = (memory.as_ptr() as usize + ARENA_ALIGN - 1) & !(ARENA_ALIGN - 1);
+        unsafe { allocator.init(start, size) };
+        Arena {
+            allocator: Locked::new(allocator),
+            start,
+            size,
+            _memory: memory,
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.allocator.lock().stats()
+    }
+}
+
+impl<G> Arena<G> {
+    /// Build an arena from an allocator that was already initialized over `memory`.
+    pub fn from_parts(allocator: G, memory: Vec<u8>, start: usize, size: usize) -> Self {
+        Arena {
+            allocator,
+            start,
+            size,
+            _memory: memory,
+        }
+    }
+}
+
+/// One step of an allocation sequence.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    /// Allocate `size` bytes aligned to `align` (which must be a power of two)
+    Alloc { size: usize, align: usize },
+    /// Free one of the live allocations, chosen by this index modulo their number
+    Dealloc(usize),
+    /// Resize one of the live allocations (chosen like for `Dealloc`) to `size` bytes
+    Realloc { index: usize, size: usize },
+}
+
+/// A xorshift pseudo-random number generator, good enough to generate allocation sequences.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on 0
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Return a number in `range`
+    pub fn gen_range(&mut self, range: std::ops::Range<usize>) -> usize {
+        range.start + (self.next_u64() % (range.end - range.start) as u64) as usize
+    }
+}
+
+/// Generate a random sequence of up to `max_len` operations, with allocations of up to
+/// `max_size` bytes.
+pub fn random_ops(rng: &mut Rng, max_len: usize, max_size: usize) -> Vec<Op> {
+    let len = rng.gen_range(0..max_len);
+    (0..len)
+        .map(|_| match rng.gen_range(0..10) {
+            // mostly small allocations
+            0..=4 => Op::Alloc {
+                size: rng.gen_range(1..512),
+                align: 1 << rng.gen_range(0..6),
+            },
+            // sometimes larger ones, with alignments up to a page
+            5 => Op::Alloc {
+                size: rng.gen_range(1..max_size),
+                align: 1 << rng.gen_range(0..13),
+            },
+            6 => Op::Realloc {
+                index: rng.next_u64() as usize,
+                size: rng.gen_range(1..1024),
+            },
+            _ => Op::Dealloc(rng.next_u64() as usize),
+        })
+        .collect()
+}
+
+/// Decode a sequence of operations from arbitrary bytes, as given by a fuzzer.
+///
+/// Each operation takes 4 bytes: a kind, a size on two bytes, and an alignment shift or an index.
+pub fn ops_from_bytes(data: &[u8]) -> Vec<Op> {
+    data.chunks_exact(4)
+        .map(|chunk| {
+            let size = usize::from(u16::from_le_bytes([chunk[1], chunk[2]])).max(1);
+            match chunk[0] % 3 {
+                0 => Op::Alloc {
+                    size,
+                    align: 1 << (chunk[3] % 13),
+                },
+                1 => Op::Realloc {
+                    index: usize::from(chunk[3]),
+                    size,
+                },
+                _ => Op::Dealloc(usize::from(chunk[3])),
+            }
+        })
+        .collect()
+}
+
+/// A live allocation and the byte it was filled with
+#[derive(Clone, Copy)]
+struct Allocation {
+    ptr: *mut u8,
+    layout: Layout,
+    fill: u8,
+}
+
+impl<G: GlobalAlloc> Arena<G> {
+    /// Run the given operations on the allocator, then free everything that is still allocated.
+    ///
+    /// Every allocation is checked to be aligned, inside the arena and disjoint from all other
+    /// live allocations. Allocations are filled with a byte of their own and checked to still
+    /// hold it when freed or resized, which catches an allocator writing its metadata in used
+    /// memory or losing data when resizing. Running out of memory is not an error.
+    pub fn replay(&self, ops: &[Op]) {
+        let mut live: Vec<Allocation> = Vec::new();
+
+        for (i, &op) in ops.iter().enumerate() {
+            match op {
+                Op::Alloc { size, align } => {
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = unsafe { self.allocator.alloc(layout) };
+                    if ptr.is_null() {
+                        continue;
+                    }
+                    self.check_new(&live, ptr, layout);
+                    let fill = i as u8;
+                    unsafe { ptr.write_bytes(fill, size) };
+                    live.push(Allocation { ptr, layout, fill });
+                }
+                Op::Dealloc(index) => {
+                    if !live.is_empty() {
+                        let allocation = live.swap_remove(index % live.len());
+                        self.free(allocation);
+                    }
+                }
+                Op::Realloc { index, size } => {
+                    if live.is_empty() {
+                        continue;
+                    }
+                    let index = index % live.len();
+                    let Allocation { ptr, layout, fill } = live[index];
+                    check_fill(ptr, layout.size(), fill);
+                    let new_ptr = unsafe { self.allocator.realloc(ptr, layout, size) };
+                    if new_ptr.is_null() {
+                        // the allocation is left untouched
+                        continue;
+                    }
+                    live.swap_remove(index);
+                    let new_layout = Layout::from_size_align(size, layout.align()).unwrap();
+                    self.check_new(&live, new_ptr, new_layout);
+                    check_fill(new_ptr, layout.size().min(size), fill);
+                    let fill = i as u8;
+                    unsafe { new_ptr.write_bytes(fill, size) };
+                    live.push(Allocation {
+                        ptr: new_ptr,
+                        layout: new_layout,
+                        fill,
+                    });
+                }
+            }
+        }
+
+        for allocation in live {
+            self.free(allocation);
+        }
+    }
+
+    /// Check that a new allocation is aligned, inside the arena and disjoint from `live`
+    fn check_new(&self, live: &[Allocation], ptr: *mut u8, layout: Layout) {
+        let (addr, size) = (ptr as usize, layout.size());
+        assert_eq!(
+            addr % layout.align(),
+            0,
+            "{:?} misaligned at {:#x}",
+            layout,
+            addr
+        );
+        assert!(
+            self.start <= addr && addr + size <= self.start + self.size,
+            "{:?} at {:#x} is outside of the arena",
+            layout,
+            addr
+        );
+        for other in live {
+            let other_addr = other.ptr as usize;
+            assert!(
+                addr + size <= other_addr || other_addr + other.layout.size() <= addr,
+                "{:?} at {:#x} overlaps {:?} at {:#x}",
+                layout,
+                addr,
+                other.layout,
+                other_addr
+            );
+        }
+    }
+
+    fn free(&self, allocation: Allocation) {
+        let Allocation { ptr, layout, fill } = allocation;
+        check_fill(ptr, layout.size(), fill);
+        unsafe { self.allocator.dealloc(ptr, layout) };
+    }
+}
+
+/// Check that the `size` bytes at `ptr` still hold `fill`
+fn check_fill(ptr: *mut u8, size: usize, fill: u8) {
+    let data = unsafe { std::slice::from_raw_parts(ptr, size) };
+    assert!(
+        data.iter().all(|&b| b == fill),
+        "{} bytes at {:p} were overwritten while allocated",
+        size,
+        ptr
+    );
+}
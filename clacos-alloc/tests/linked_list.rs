@@ -0,0 +1,165 @@
+mod common;
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use clacos_alloc::linked_list::{FitPolicy, LinkedListAllocator};
+use common::Arena;
+
+const ARENA_SIZE: usize = 4096;
+
+/// Check that freeing a region right before a free one merges them (and only them)
+#[test]
+fn free_merges_with_next_region() {
+    let arena = Arena::new(LinkedListAllocator::new(), ARENA_SIZE);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let first = unsafe { arena.allocator.alloc(layout) };
+    let second = unsafe { arena.allocator.alloc(layout) };
+
+    // `second` is right before the rest of the arena
+    unsafe { arena.allocator.dealloc(second, layout) };
+    let stats = arena.stats();
+    assert_eq!(stats.free, ARENA_SIZE - 64);
+    assert_eq!(stats.free_regions, 1);
+
+    // `first` is right before the merged region
+    unsafe { arena.allocator.dealloc(first, layout) };
+    let stats = arena.stats();
+    assert_eq!(stats.free, ARENA_SIZE);
+    assert_eq!(stats.free_regions, 1);
+}
+
+/// Check that the memory skipped to align an allocation stays available
+#[test]
+fn alignment_padding_is_not_lost() {
+    let arena = Arena::new(LinkedListAllocator::new(), ARENA_SIZE);
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let aligned = Layout::from_size_align(256, 256).unwrap();
+
+    let first = unsafe { arena.allocator.alloc(small) };
+    let second = unsafe { arena.allocator.alloc(aligned) };
+    assert_eq!(second as usize % 256, 0);
+    assert_eq!(arena.stats().free, ARENA_SIZE - 16 - 256);
+
+    unsafe {
+        arena.allocator.dealloc(first, small);
+        arena.allocator.dealloc(second, aligned);
+    }
+    let stats = arena.stats();
+    assert_eq!(stats.free, ARENA_SIZE);
+    assert_eq!(stats.free_regions, 1);
+}
+
+/// Check that an allocation followed by free memory grows without moving
+#[test]
+fn realloc_grows_in_place() {
+    let arena = Arena::new(LinkedListAllocator::new(), ARENA_SIZE);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = unsafe { arena.allocator.alloc(layout) };
+    unsafe { ptr.write_bytes(0xab, 64) };
+
+    let grown = unsafe { arena.allocator.realloc(ptr, layout, 1024) };
+    assert_eq!(grown, ptr);
+    let data = unsafe { std::slice::from_raw_parts(grown, 64) };
+    assert!(data.iter().all(|&b| b == 0xab));
+    assert_eq!(arena.stats().free, ARENA_SIZE - 1024);
+    assert_eq!(arena.stats().allocations, 1);
+
+    unsafe {
+        arena
+            .allocator
+            .dealloc(grown, Layout::from_size_align(1024, 8).unwrap())
+    };
+    assert_eq!(arena.stats().free, ARENA_SIZE);
+    assert_eq!(arena.stats().free_regions, 1);
+}
+
+/// Check that an allocation followed by another one moves to grow
+#[test]
+fn realloc_moves_when_blocked() {
+    let arena = Arena::new(LinkedListAllocator::new(), ARENA_SIZE);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = unsafe { arena.allocator.alloc(layout) };
+    let blocker = unsafe { arena.allocator.alloc(layout) };
+    unsafe { ptr.write_bytes(0xab, 64) };
+
+    let grown = unsafe { arena.allocator.realloc(ptr, layout, 1024) };
+    assert_ne!(grown, ptr);
+    let data = unsafe { std::slice::from_raw_parts(grown, 64) };
+    assert!(data.iter().all(|&b| b == 0xab));
+
+    unsafe {
+        arena.allocator.dealloc(blocker, layout);
+        arena
+            .allocator
+            .dealloc(grown, Layout::from_size_align(1024, 8).unwrap());
+    }
+    assert_eq!(arena.stats().free, ARENA_SIZE);
+    assert_eq!(arena.stats().free_regions, 1);
+}
+
+/// Check that shrinking gives the tail of the allocation back
+#[test]
+fn realloc_shrinks_in_place() {
+    let arena = Arena::new(LinkedListAllocator::new(), ARENA_SIZE);
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+    let ptr = unsafe { arena.allocator.alloc(layout) };
+    let blocker = unsafe { arena.allocator.alloc(layout) };
+
+    let shrunk = unsafe { arena.allocator.realloc(ptr, layout, 64) };
+    assert_eq!(shrunk, ptr);
+    assert_eq!(arena.stats().free, ARENA_SIZE - 1024 - 64);
+    assert_eq!(arena.stats().free_regions, 2);
+
+    unsafe {
+        arena
+            .allocator
+            .dealloc(shrunk, Layout::from_size_align(64, 8).unwrap());
+        arena.allocator.dealloc(blocker, layout);
+    }
+    assert_eq!(arena.stats().free, ARENA_SIZE);
+    assert_eq!(arena.stats().free_regions, 1);
+}
+
+/// Check that next-fit resumes looking after the region of the previous allocation, and wraps
+/// around at the end of the list
+#[test]
+fn next_fit_resumes_after_previous_allocation() {
+    let arena = Arena::new(
+        LinkedListAllocator::with_policy(FitPolicy::NextFit),
+        ARENA_SIZE,
+    );
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let large = Layout::from_size_align(128, 8).unwrap();
+    let smalls: Vec<*mut u8> = (0..4)
+        .map(|_| unsafe { arena.allocator.alloc(small) })
+        .collect();
+    unsafe {
+        arena.allocator.dealloc(smalls[0], small);
+        arena.allocator.dealloc(smalls[2], small);
+    }
+
+    // the holes are too small, so this one comes from the end of the arena
+    let big = unsafe { arena.allocator.alloc(large) };
+    assert_eq!(big as usize, smalls[3] as usize + 64);
+    // next-fit keeps going from there, where first-fit would fill the first hole
+    let next = unsafe { arena.allocator.alloc(small) };
+    assert_eq!(next as usize, big as usize + 128);
+
+    // fill the end of the arena, then wrap around to the first hole
+    let tail_layout = Layout::from_size_align(ARENA_SIZE - 4 * 64 - 128 - 64, 8).unwrap();
+    let tail = unsafe { arena.allocator.alloc(tail_layout) };
+    assert!(!tail.is_null());
+    let wrapped = unsafe { arena.allocator.alloc(small) };
+    assert_eq!(wrapped, smalls[0]);
+
+    unsafe {
+        arena.allocator.dealloc(wrapped, small);
+        arena.allocator.dealloc(smalls[1], small);
+        arena.allocator.dealloc(smalls[3], small);
+        arena.allocator.dealloc(big, large);
+        arena.allocator.dealloc(next, small);
+        arena.allocator.dealloc(tail, tail_layout);
+    }
+    assert_eq!(arena.stats().free, ARENA_SIZE);
+    assert_eq!(arena.stats().free_regions, 1);
+}
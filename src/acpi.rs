@@ -0,0 +1,308 @@
+//! Discovery of the ACPI tables describing the hardware, like the MADT (interrupt controllers).
+//!
+//! The RSDP is searched for where legacy BIOSes put it (the bootloader only boots with one): in
+//! the first KiB of the extended BIOS data area, then in the BIOS area at 0xe0000..0x100000. It
+//! points to the RSDT (or XSDT for ACPI 2.0+), listing the physical addresses of the other tables.
+//!
+//! Every table is read in place through the physical memory mapping of the bootloader, which
+//! covers everything up to the end of the last region of the memory map, ACPI regions included.
+//!
+//! Main drawbacks:
+//! - UEFI systems, which pass the RSDP to the bootloader instead, aren't supported
+//! - the tables are parsed again on every lookup
+
+use core::{mem::size_of, ptr, slice};
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+
+/// Physical address of the segment of the extended BIOS data area, in the BIOS data area
+const EBDA_SEGMENT_ADDR: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The Root System Description Pointer, with the fields added by ACPI 2.0
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_addr: u32,
+    // ACPI 2.0+ only
+    length: u32,
+    xsdt_addr: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the RSDP of ACPI 1.0, covered by its first checksum
+const RSDP_V1_SIZE: usize = 20;
+
+/// The header common to every System Description Table
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// An ACPI table found in memory
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    phys: PhysAddr,
+    virt: VirtAddr,
+}
+
+impl Table {
+    /// Return the table at `phys` if its checksum is right, with `phys_offset` the offset of the
+    /// physical memory mapping
+    fn at(phys: PhysAddr, phys_offset: VirtAddr) -> Option<Table> {
+        let table = Table {
+            phys,
+            virt: phys_offset + phys.as_u64(),
+        };
+        checksum(table.bytes()).then_some(table)
+    }
+
+    /// Return the physical address of the table
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Return the header of the table
+    pub fn header(&self) -> SdtHeader {
+        unsafe { ptr::read_unaligned(self.virt.as_ptr()) }
+    }
+
+    /// Return the whole table, header included
+    pub fn bytes(&self) -> &'static [u8] {
+        let length = self.header().length as usize;
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), length) }
+    }
+
+    /// Return the content of the table, after the header
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes()[size_of::<SdtHeader>()..]
+    }
+}
+
+/// Return whether the bytes sum to 0, as every ACPI structure does
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Return the RSDP if it is in the `size` bytes of physical memory at `start`
+fn search_rsdp(start: u64, size: u64, phys_offset: VirtAddr) -> Option<Rsdp> {
+    // the RSDP is on a 16 bytes boundary
+    (start..start + size).step_by(16).find_map(|addr| {
+        let virt = phys_offset + addr;
+        let signature: [u8; 8] = unsafe { ptr::read_unaligned(virt.as_ptr()) };
+        if &signature != RSDP_SIGNATURE {
+            return None;
+        }
+        let bytes = unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), RSDP_V1_SIZE) };
+        if !checksum(bytes) {
+            return None;
+        }
+        let mut rsdp: Rsdp = unsafe { ptr::read_unaligned(virt.as_ptr()) };
+        if rsdp.revision < 2 {
+            rsdp.xsdt_addr = 0;
+        }
+        Some(rsdp)
+    })
+}
+
+/// Find the RSDP in the extended BIOS data area or the BIOS area
+fn find_rsdp(phys_offset: VirtAddr) -> Option<Rsdp> {
+    let ebda_segment: u16 =
+        unsafe { ptr::read_unaligned((phys_offset + EBDA_SEGMENT_ADDR).as_ptr()) };
+    let ebda = u64::from(ebda_segment) << 4;
+    (ebda != 0)
+        .then(|| search_rsdp(ebda, 1024, phys_offset))
+        .flatten()
+        .or_else(|| {
+            search_rsdp(
+                BIOS_AREA_START,
+                BIOS_AREA_END - BIOS_AREA_START,
+                phys_offset,
+            )
+        })
+}
+
+/// Call `f` on every table listed in the RSDT (or XSDT), until it returns `Some`.
+///
+/// Return None if `memory::make_global` wasn't called yet, if there are no ACPI tables, or if `f`
+/// never returns `Some`.
+fn find_map_table<R>(mut f: impl FnMut(Table) -> Option<R>) -> Option<R> {
+    let phys_offset = memory::with_global_paging(|mapper, _| mapper.phys_offset())?;
+    let rsdp = find_rsdp(phys_offset)?;
+
+    // the XSDT has 64 bits entries, the RSDT 32 bits ones
+    let (root, entry_size) = if rsdp.xsdt_addr != 0 {
+        (PhysAddr::new(rsdp.xsdt_addr), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_addr)), 4)
+    };
+    let root = Table::at(root, phys_offset)?;
+    root.data().chunks_exact(entry_size).find_map(|entry| {
+        let addr = entry
+            .iter()
+            .rev()
+            .fold(0u64, |addr, byte| (addr << 8) | u64::from(*byte));
+        Table::at(PhysAddr::new(addr), phys_offset).and_then(&mut f)
+    })
+}
+
+/// Return the first table with the given signature (like `b"APIC"` for the MADT).
+///
+/// Return None if `memory::make_global` wasn't called yet or if there is no such table.
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    find_map_table(|table| (&table.header().signature == signature).then_some(table))
+}
+
+/// The Multiple APIC Description Table, describing the interrupt controllers
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    table: Table,
+}
+
+/// An entry of the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    /// A processor and its local APIC
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    /// An I/O APIC, handling the global system interrupts from `gsi_base` on
+    IoApic {
+        id: u8,
+        addr: PhysAddr,
+        gsi_base: u32,
+    },
+    /// An ISA IRQ wired to another global system interrupt, or with another polarity or trigger
+    /// mode than the ISA ones (see `MPS_INTI_*`)
+    InterruptSourceOverride { irq: u8, gsi: u32, flags: u16 },
+    /// The 64 bits address of the local APICs, overriding the one in the header of the table
+    LocalApicAddressOverride { addr: PhysAddr },
+    /// Another kind of entry, with its type
+    Other(u8),
+}
+
+/// Mask of the polarity in the flags of an interrupt source override
+pub const MPS_INTI_POLARITY: u16 = 0b11;
+/// Active low polarity
+pub const MPS_INTI_ACTIVE_LOW: u16 = 0b11;
+/// Mask of the trigger mode in the flags of an interrupt source override
+pub const MPS_INTI_TRIGGER: u16 = 0b11 << 2;
+/// Level-triggered mode
+pub const MPS_INTI_LEVEL: u16 = 0b11 << 2;
+
+/// Flag of the MADT telling that the system has 8259 PICs too
+pub const PCAT_COMPAT: u32 = 1;
+
+impl Madt {
+    /// Find the MADT, if there is one.
+    pub fn find() -> Option<Madt> {
+        find_table(b"APIC").map(|table| Madt { table })
+    }
+
+    /// Return the physical address of the local APICs
+    pub fn local_apic_addr(&self) -> PhysAddr {
+        let addr = self.entries().find_map(|entry| match entry {
+            MadtEntry::LocalApicAddressOverride { addr } => Some(addr),
+            _ => None,
+        });
+        addr.unwrap_or_else(|| PhysAddr::new(u64::from(read_u32(self.table.data(), 0))))
+    }
+
+    /// Return the flags of the MADT (see `PCAT_COMPAT`)
+    pub fn flags(&self) -> u32 {
+        read_u32(self.table.data(), 4)
+    }
+
+    /// Return the entries of the MADT
+    pub fn entries(&self) -> impl Iterator<Item = MadtEntry> {
+        // the entries follow the address and flags of the local APIC
+        let mut entries = &self.table.data()[8..];
+        core::iter::from_fn(move || {
+            let (kind, length) = match entries {
+                [kind, length, ..] if *length >= 2 && usize::from(*length) <= entries.len() => {
+                    (*kind, usize::from(*length))
+                }
+                _ => return None,
+            };
+            let entry = &entries[..length];
+            entries = &entries[length..];
+            Some(parse_madt_entry(kind, entry).unwrap_or(MadtEntry::Other(kind)))
+        })
+    }
+}
+
+/// Parse the MADT entry of type `kind`, returning None if it is too short
+fn parse_madt_entry(kind: u8, entry: &[u8]) -> Option<MadtEntry> {
+    let parsed = match kind {
+        0 if entry.len() >= 8 => MadtEntry::LocalApic {
+            processor_id: entry[2],
+            apic_id: entry[3],
+            flags: read_u32(entry, 4),
+        },
+        1 if entry.len() >= 12 => MadtEntry::IoApic {
+            id: entry[2],
+            addr: PhysAddr::new(u64::from(read_u32(entry, 4))),
+            gsi_base: read_u32(entry, 8),
+        },
+        2 if entry.len() >= 10 => MadtEntry::InterruptSourceOverride {
+            irq: entry[3],
+            gsi: read_u32(entry, 4),
+            flags: u16::from_le_bytes([entry[8], entry[9]]),
+        },
+        5 if entry.len() >= 12 => MadtEntry::LocalApicAddressOverride {
+            addr: PhysAddr::new(u64::from_le_bytes(entry[4..12].try_into().ok()?)),
+        },
+        _ => return None,
+    };
+    Some(parsed)
+}
+
+/// The HPET Description Table, describing the High Precision Event Timer
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable {
+    table: Table,
+}
+
+/// Address space of a Generic Address Structure in system memory (as opposed to I/O ports)
+const GAS_SYSTEM_MEMORY: u8 = 0;
+
+impl HpetTable {
+    /// Find the HPET table, if there is one.
+    pub fn find() -> Option<HpetTable> {
+        find_table(b"HPET").map(|table| HpetTable { table })
+    }
+
+    /// Return the physical address of the registers of the HPET, unless they aren't in memory
+    pub fn registers_addr(&self) -> Option<PhysAddr> {
+        // a Generic Address Structure follows the ID of the event timer block
+        let data = self.table.data();
+        let addr = u64::from_le_bytes(data.get(8..16)?.try_into().ok()?);
+        (data[4] == GAS_SYSTEM_MEMORY).then(|| PhysAddr::new(addr))
+    }
+}
+
+/// Read the little endian `u32` at `offset` in `bytes`
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
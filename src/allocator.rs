@@ -1,69 +1,340 @@
-pub mod bump;
-pub mod linked_list;
+use alloc::alloc::{GlobalAlloc, Layout};
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, PageSize, PageTableFlags, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
-use linked_list::LinkedListAllocator;
 
-pub const HEAP_START: usize = 0x4444_4444_0000;
+use crate::{
+    memory::{self, vm},
+    println, serial_println,
+};
+
+pub mod slab;
+
+// The allocator backends live in their own crate so that they can be tested on the host
+pub use clacos_alloc::{
+    buddy, bump, debug, fixed_size_block, linked_list, HeapBackend, HeapStats, Locked,
+};
+
+/// Size of the virtual memory reserved for the heap, which bounds `set_max_heap_size`
+pub const HEAP_RESERVED_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+/// Size of the heap mapped by `init_heap`, which then grows on demand up to `HEAP_MAX_SIZE`
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Default upper bound of the heap size, see `set_max_heap_size`
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+
+// The global allocator backend is chosen at compile time with the `alloc-*` cargo features,
+// exactly one of which must be enabled.
+#[cfg(feature = "alloc-buddy")]
+use buddy::BuddyAllocator as Backend;
+#[cfg(feature = "alloc-bump")]
+use bump::BumpAllocator as Backend;
+#[cfg(feature = "alloc-fixed-size-block")]
+use fixed_size_block::FixedSizeBlockAllocator as Backend;
+#[cfg(feature = "alloc-linked-list")]
+use linked_list::LinkedListAllocator as Backend;
 
+const _: () = assert!(
+    cfg!(feature = "alloc-buddy") as usize
+        + cfg!(feature = "alloc-bump") as usize
+        + cfg!(feature = "alloc-fixed-size-block") as usize
+        + cfg!(feature = "alloc-linked-list") as usize
+        == 1,
+    "exactly one global allocator backend feature (`alloc-*`) must be enabled"
+);
+
+#[cfg(not(feature = "debug-heap"))]
 #[global_allocator]
-static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64; // address of the last byte *in* the heap
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+static ALLOCATOR: Heap<Backend> = Heap::new(Backend::new());
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        }
-    }
+// With the `debug-heap` feature, the whole heap is wrapped in a `DebugAllocator` to catch heap
+// corruption bugs.
+#[cfg(feature = "debug-heap")]
+#[global_allocator]
+static ALLOCATOR: debug::DebugAllocator<Heap<Backend>> =
+    debug::DebugAllocator::new(Heap::new(Backend::new()));
+
+/// Return the kernel heap, whether it is wrapped by a `DebugAllocator` or not
+fn heap() -> &'static Heap<Backend> {
+    #[cfg(feature = "debug-heap")]
+    return ALLOCATOR.inner();
+    #[cfg(not(feature = "debug-heap"))]
+    return &ALLOCATOR;
+}
+
+/// Reserve the heap's virtual memory, map its initial pages and initialize the global allocator
+/// with them.
+///
+/// Once the mapper and frame allocator are handed over with `memory::make_global`, the heap grows
+/// by itself when it runs out of memory.
+///
+/// Huge pages are used where the heap is aligned for them.
+pub fn init_heap<M, A>(mapper: &mut M, frame_allocator: &mut A) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    // aligned for huge pages, so that it can use them as soon as it is large enough
+    let region = vm::reserve(
+        HEAP_RESERVED_SIZE as u64,
+        Size2MiB::SIZE,
+        HEAP_PAGE_FLAGS,
+        vm::RegionKind::Heap,
+    )
+    .expect("no virtual memory left for the heap");
+    let size = x86_64::align_up(HEAP_SIZE as u64, Size4KiB::SIZE);
+    memory::map_range(
+        region.start(),
+        size,
+        HEAP_PAGE_FLAGS,
+        mapper,
+        frame_allocator,
+    )?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        heap().init(region.start().as_u64() as usize, HEAP_SIZE);
     }
 
     Ok(())
 }
 
-/// Wrapper type based on spin::Mutex to enable interior mutability
-pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+/// Change the maximum size the heap can grow to (`HEAP_MAX_SIZE` by default), up to
+/// `HEAP_RESERVED_SIZE`.
+///
+/// Memory that is already mapped stays in the heap, even if it is above the new limit.
+pub fn set_max_heap_size(max_size: usize) {
+    heap().bounds.lock().max_size = max_size.min(HEAP_RESERVED_SIZE);
+}
+
+/// Return the address of the start of the heap (0 before `init_heap`).
+pub fn heap_start() -> usize {
+    heap().bounds.lock().start
+}
+
+/// Return the current size of the heap, that is how much memory is mapped for it.
+pub fn heap_size() -> usize {
+    heap().bounds.lock().size
+}
+
+/// Return a snapshot of the state of the kernel heap.
+///
+/// With the `debug-heap` feature, the sizes include the debug metadata and the quarantined
+/// allocations.
+pub fn heap_stats() -> HeapStats {
+    heap().backend.lock().stats()
 }
 
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: spin::Mutex::new(inner),
+/// A function called when the heap is out of memory, to free some memory for the failed
+/// allocation. It returns whether it could free anything, in which case the allocation is retried.
+///
+/// It may deallocate but must not allocate from the heap.
+pub type ReclaimFn = fn(Layout) -> bool;
+
+static RECLAIM: spin::Mutex<Option<ReclaimFn>> = spin::Mutex::new(None);
+
+/// Register the function to call when the heap is out of memory, replacing the previous one.
+///
+/// Without one (or when it can't free anything), the allocation fails.
+pub fn set_reclaim_handler(reclaim: Option<ReclaimFn>) {
+    *RECLAIM.lock() = reclaim;
+}
+
+/// Print the failed allocation and the state of the heap, on screen and over serial.
+///
+/// Called by the allocation error handler, which panics right after that for infallible
+/// allocations (e.g. `Box::new`), so this is the last chance to tell why.
+pub fn report_out_of_memory(layout: Layout) {
+    let stats = heap_stats();
+    let (size, max_size) = {
+        let bounds = heap().bounds.lock();
+        (bounds.size, bounds.max_size)
+    };
+    // the same report on both outputs
+    macro_rules! report {
+        ($print:ident) => {
+            $print!(
+                "out of memory: failed to allocate {:?}\n\
+                 heap: {} of {} bytes mapped, {} used, {} free ({} regions, largest {}), \
+                 {} live allocations",
+                layout,
+                size,
+                max_size,
+                stats.used,
+                stats.free,
+                stats.free_regions,
+                stats.largest_free_block,
+                stats.live_allocations(),
+            )
+        };
+    }
+    report!(println);
+    report!(serial_println);
+}
+
+/// Change how the kernel heap chooses where to place allocations.
+#[cfg(feature = "alloc-linked-list")]
+pub fn set_fit_policy(policy: linked_list::FitPolicy) {
+    heap().backend.lock().set_policy(policy);
+}
+
+/// Print the free list of the kernel heap to serial.
+#[cfg(feature = "alloc-linked-list")]
+pub fn dump_free_list() {
+    let allocator = heap().backend.lock();
+    serial_println!("LinkedListAllocator free list:");
+    for (start, size) in allocator.free_regions() {
+        serial_println!("    {:#x}..{:#x} ({} bytes)", start, start + size, size);
+    }
+}
+
+/// Flags of the pages of the heap
+const HEAP_PAGE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// The kernel heap: an allocator backend that can grow by mapping more pages after the end of the
+/// memory it manages.
+pub struct Heap<A> {
+    backend: Locked<A>,
+    bounds: spin::Mutex<HeapBounds>,
+}
+
+struct HeapBounds {
+    start: usize,
+    size: usize,
+    max_size: usize,
+}
+
+impl<A> Heap<A> {
+    pub const fn new(backend: A) -> Self {
+        Heap {
+            backend: Locked::new(backend),
+            bounds: spin::Mutex::new(HeapBounds {
+                start: 0,
+                size: 0,
+                max_size: HEAP_MAX_SIZE,
+            }),
         }
     }
+}
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+impl<A: HeapBackend> Heap<A> {
+    /// Initialize the heap with the given (already mapped) bounds.
+    ///
+    /// # Safety
+    ///
+    /// Same as `HeapBackend::init`. Additionally, the virtual memory right after the heap must be
+    /// unused so that the heap can grow into it.
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        let mut bounds = self.bounds.lock();
+        bounds.start = heap_start;
+        bounds.size = heap_size;
+        unsafe { self.backend.lock().init(heap_start, heap_size) };
+    }
+
+    /// Try to map enough memory after the end of the heap for the given layout to fit, and hand
+    /// it to the backend.
+    ///
+    /// Return whether the heap did grow.
+    fn grow(&self, layout: Layout) -> bool {
+        // Interrupt handlers could try to grow the heap too, which would deadlock on `bounds`
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut bounds = self.bounds.lock();
+            let end = bounds.start + bounds.size;
+
+            // Grow by at least the current size, so that the number of regions handed to the
+            // backend stays logarithmic in the heap size. Some backends (like the buddy
+            // allocator) can't use the whole region for the allocation, but a too small growth
+            // only means that the allocation will trigger another one.
+            let wanted = (layout.size() + layout.align()).max(bounds.size);
+            let size = (x86_64::align_up(wanted as u64, Size4KiB::SIZE) as usize)
+                .min(bounds.max_size.saturating_sub(bounds.size));
+            if size == 0 {
+                return false;
+            }
+
+            // If the frames run out halfway through, keep the pages that could be mapped
+            let mut mapped = 0;
+            memory::with_global_paging(|mapper, frame_allocator| {
+                while mapped < size {
+                    let addr = VirtAddr::new((end + mapped) as u64);
+                    let max_size = (size - mapped) as u64;
+                    match memory::map_next_page(
+                        addr,
+                        max_size,
+                        HEAP_PAGE_FLAGS,
+                        mapper,
+                        frame_allocator,
+                    ) {
+                        Ok(page_size) => mapped += page_size as usize,
+                        Err(_) => break,
+                    }
+                }
+            });
+            if mapped == 0 {
+                return false;
+            }
+
+            unsafe { self.backend.lock().add_free_region(end, mapped) };
+            bounds.size += mapped;
+            true
+        })
+    }
+
+    /// Call the registered reclaim function, if any, and return whether it freed memory.
+    fn reclaim(&self, layout: Layout) -> bool {
+        // copy the function out of the lock, so that it may call `set_reclaim_handler` itself
+        let reclaim = *RECLAIM.lock();
+        reclaim.map_or(false, |reclaim| reclaim(layout))
     }
 }
 
-/// Align the given address upwards to a multiple of `align`.
-///
-/// Requires that `align` is a power of two
-fn fast_align_up(addr: usize, align: usize) -> usize {
-    (addr + align - 1) & !(align - 1)
+unsafe impl<A: HeapBackend> GlobalAlloc for Heap<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let ptr = unsafe { self.backend.alloc(layout) };
+            if !ptr.is_null() {
+                return ptr;
+            }
+            if self.grow(layout) {
+                continue;
+            }
+            // retry only as long as the reclaim function actually frees memory, otherwise it
+            // could keep claiming to do so forever
+            let before = self.backend.lock().stats();
+            if !self.reclaim(layout) || self.backend.lock().stats() == before {
+                // fallible allocations (e.g. `Vec::try_reserve`) handle this quietly, infallible
+                // ones end in `report_out_of_memory` through the allocation error handler
+                return ptr;
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.backend.dealloc(ptr, layout) }
+    }
+
+    /// Resize in place if the backend can, otherwise move the allocation (growing the heap if
+    /// needed).
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if unsafe { self.backend.lock().resize_in_place(ptr, layout, new_size) } {
+            return ptr;
+        }
+
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
 }
@@ -0,0 +1,328 @@
+//! Caches of same-sized kernel objects, each backed by whole frames.
+//!
+//! A `SlabCache<T>` hands out slots for values of type `T` from slabs: frames taken from the global
+//! frame allocator and accessed through the physical memory mapping, so that they don't go through
+//! the heap at all. Freed slots go back to their slab's free list and are reused by the following
+//! allocations of the same cache. A slab that becomes empty gives its frame back, unless it is the
+//! only empty slab of the cache, which is kept for the next allocations.
+//!
+//! Main drawbacks:
+//!
+//! - finding a slab with a free slot, or the slab before an empty one to free it, walks the list
+//!   of slabs
+//! - types that don't fit in a slab (or that need more than page alignment) can't be cached
+//!
+//! ```ignore
+//! static TASKS: SlabCache<Task> = SlabCache::new("tasks");
+//!
+//! let task = TASKS.alloc(Task::new()).expect("out of memory");
+//! ```
+
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory;
+
+/// Size of a slab, which is one frame
+const SLAB_SIZE: usize = Page::<Size4KiB>::SIZE as usize;
+
+/// The metadata stored at the start of every slab.
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    /// First free slot of the slab, each free slot storing the address of the next one
+    free: Option<NonNull<FreeSlot>>,
+    /// Number of slots in use
+    used: usize,
+}
+
+/// What a slot contains while it is free
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// The slabs of a cache and its counters
+struct Slabs {
+    head: Option<NonNull<SlabHeader>>,
+    count: usize,
+    /// Number of slabs without any slot in use, which is at most one
+    empty: usize,
+    used: usize,
+    allocations: usize,
+    deallocations: usize,
+}
+
+// The slabs are only ever accessed through the cache's lock
+unsafe impl Send for Slabs {}
+
+/// A snapshot of the state of a slab cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Name of the cache, as given to `SlabCache::new`
+    pub name: &'static str,
+    /// Size of a slot, that is the size of the object rounded up to its alignment (and large
+    /// enough to link free slots)
+    pub object_size: usize,
+    /// Number of slots in each slab
+    pub objects_per_slab: usize,
+    /// Number of slabs (frames) taken by the cache
+    pub slabs: usize,
+    /// Number of slots in use
+    pub used_objects: usize,
+    /// Number of slots available without taking another frame
+    pub free_objects: usize,
+    /// Number of successful allocations since the cache was created
+    pub allocations: usize,
+    /// Number of deallocations since the cache was created
+    pub deallocations: usize,
+}
+
+/// A cache of objects of type `T`.
+///
+/// Meant to be declared as a static, one per kernel object type.
+pub struct SlabCache<T> {
+    name: &'static str,
+    slabs: spin::Mutex<Slabs>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> SlabCache<T> {
+    /// Alignment of the slots
+    const SLOT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeSlot>());
+    /// Size of the slots
+    const SLOT_SIZE: usize = align_up(
+        max(mem::size_of::<T>(), mem::size_of::<FreeSlot>()),
+        Self::SLOT_ALIGN,
+    );
+    /// Offset of the first slot in its slab, right after the header
+    const FIRST_SLOT: usize = align_up(mem::size_of::<SlabHeader>(), Self::SLOT_ALIGN);
+    /// Number of slots in a slab, checked at compile time to be at least one
+    const SLOTS_PER_SLAB: usize = {
+        let count = SLAB_SIZE.saturating_sub(Self::FIRST_SLOT) / Self::SLOT_SIZE;
+        assert!(count > 0, "type too large or too aligned for a slab cache");
+        count
+    };
+
+    /// Create an empty cache, which takes its first slab on its first allocation.
+    pub const fn new(name: &'static str) -> Self {
+        // force the evaluation (and so the checks) of the slots per slab
+        let _ = Self::SLOTS_PER_SLAB;
+        SlabCache {
+            name,
+            slabs: spin::Mutex::new(Slabs {
+                head: None,
+                count: 0,
+                empty: 0,
+                used: 0,
+                allocations: 0,
+                deallocations: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move `value` into a free slot of the cache.
+    ///
+    /// Return None (dropping `value`) if a new slab is needed but no frame is available, or if the
+    /// frame allocator wasn't handed over with `memory::make_global` yet.
+    pub fn alloc(&self, value: T) -> Option<SlabBox<'_, T>> {
+        // Interrupt handlers could use the cache too, which would deadlock on `slabs`
+        let slot = interrupts::without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            let slab = match Self::find_free_slab(&slabs) {
+                Some(slab) => slab,
+                None => {
+                    let slab = Self::new_slab(slabs.head)?;
+                    slabs.head = Some(slab);
+                    slabs.count += 1;
+                    slabs.empty += 1;
+                    slab
+                }
+            };
+
+            let slab = unsafe { &mut *slab.as_ptr() };
+            let slot = slab.free.expect("slab without free slot");
+            slab.free = unsafe { slot.as_ref().next };
+            if slab.used == 0 {
+                slabs.empty -= 1;
+            }
+            slab.used += 1;
+            slabs.used += 1;
+            slabs.allocations += 1;
+            Some(slot.cast::<T>())
+        })?;
+
+        unsafe { slot.as_ptr().write(value) };
+        Some(SlabBox {
+            ptr: slot,
+            cache: self,
+        })
+    }
+
+    /// Return a snapshot of the state of the cache.
+    pub fn stats(&self) -> SlabStats {
+        interrupts::without_interrupts(|| {
+            let slabs = self.slabs.lock();
+            SlabStats {
+                name: self.name,
+                object_size: Self::SLOT_SIZE,
+                objects_per_slab: Self::SLOTS_PER_SLAB,
+                slabs: slabs.count,
+                used_objects: slabs.used,
+                free_objects: slabs.count * Self::SLOTS_PER_SLAB - slabs.used,
+                allocations: slabs.allocations,
+                deallocations: slabs.deallocations,
+            }
+        })
+    }
+
+    /// Return the first slab with a free slot, if any.
+    fn find_free_slab(slabs: &Slabs) -> Option<NonNull<SlabHeader>> {
+        let mut slab = slabs.head;
+        while let Some(current) = slab {
+            let header = unsafe { current.as_ref() };
+            if header.free.is_some() {
+                return Some(current);
+            }
+            slab = header.next;
+        }
+        None
+    }
+
+    /// Take a frame for a new slab and initialize its header and free list.
+    fn new_slab(next: Option<NonNull<SlabHeader>>) -> Option<NonNull<SlabHeader>> {
+        let start = memory::with_global_paging(|mapper, frame_allocator| {
+            let frame: PhysFrame = frame_allocator.allocate_frame()?;
+            Some(mapper.phys_offset() + frame.start_address().as_u64())
+        })??;
+
+        // link every slot to the following one, the last one to nothing
+        let mut free = None;
+        for i in (0..Self::SLOTS_PER_SLAB).rev() {
+            let slot = (start + Self::FIRST_SLOT + i * Self::SLOT_SIZE).as_mut_ptr::<FreeSlot>();
+            unsafe { slot.write(FreeSlot { next: free }) };
+            free = NonNull::new(slot);
+        }
+
+        let header = start.as_mut_ptr::<SlabHeader>();
+        unsafe {
+            header.write(SlabHeader {
+                next,
+                free,
+                used: 0,
+            })
+        };
+        NonNull::new(header)
+    }
+
+    /// Give the slot at `ptr` back to its slab.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr` was allocated by this
+    /// cache, and that the value it contained was already dropped.
+    unsafe fn free(&self, ptr: NonNull<T>) {
+        // slabs are aligned frames, so the header of a slot's slab is at the start of its frame
+        let header = (ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let slot = ptr.cast::<FreeSlot>();
+
+        interrupts::without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            let slab = unsafe { &mut *header };
+            unsafe { slot.as_ptr().write(FreeSlot { next: slab.free }) };
+            slab.free = Some(slot);
+            slab.used -= 1;
+            slabs.used -= 1;
+            slabs.deallocations += 1;
+            if slab.used == 0 {
+                if slabs.empty == 0 {
+                    slabs.empty += 1;
+                } else {
+                    unsafe { Self::free_slab(&mut slabs, NonNull::new(header).unwrap()) };
+                }
+            }
+        });
+    }
+
+    /// Unlink the slab at `slab` and give its frame back to the frame allocator.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that `slab` is one of `slabs`,
+    /// and that none of its slots is in use.
+    unsafe fn free_slab(slabs: &mut Slabs, slab: NonNull<SlabHeader>) {
+        let next = unsafe { slab.as_ref().next };
+        if slabs.head == Some(slab) {
+            slabs.head = next;
+        } else {
+            let mut previous = slabs.head.expect("slab not in its cache");
+            while unsafe { previous.as_ref().next } != Some(slab) {
+                previous = unsafe { previous.as_ref().next }.expect("slab not in its cache");
+            }
+            unsafe { previous.as_mut().next = next };
+        }
+        slabs.count -= 1;
+
+        memory::with_global_paging(|mapper, frame_allocator| {
+            let addr = VirtAddr::from_ptr(slab.as_ptr()) - mapper.phys_offset().as_u64();
+            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr.as_u64()));
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        })
+        .expect("slab frames are taken from the global frame allocator");
+    }
+}
+
+/// A value allocated in a `SlabCache`, which gives its slot back when dropped.
+pub struct SlabBox<'a, T> {
+    ptr: NonNull<T>,
+    cache: &'a SlabCache<T>,
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free(self.ptr);
+        }
+    }
+}
+
+// Same as `Box<T>`
+unsafe impl<T: Send> Send for SlabBox<'_, T> {}
+unsafe impl<T: Sync> Sync for SlabBox<'_, T> {}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Align the given address `addr` upwards to alignment `align`, which must be a power of two.
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,381 @@
+//! The local APIC and I/O APIC interrupt controllers, replacing the legacy 8259 PICs.
+//!
+//! `init` finds the I/O APICs through the ACPI MADT, masks the PICs, enables the local APIC of the
+//! current CPU, and routes the ISA IRQs with a handler (see `interrupts::register_irq`), the timer
+//! and the keyboard ones at least, through the I/O APICs to the same vectors as with the PICs, so
+//! that their handlers don't change. From then on, the end of interrupts is signaled to the local
+//! APIC.
+//!
+//! The registers of the APICs are mapped in `Mmio` regions of the virtual memory manager.
+//!
+//! Main drawbacks:
+//! - only the current CPU (the bootstrap processor) is set up, and every IRQ is sent to it
+//! - the local APIC is used in xAPIC mode (through memory-mapped registers), not in x2APIC mode
+
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::model_specific::Msr,
+    structures::paging::{PageSize, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::{self, Madt, MadtEntry},
+    interrupts::{self, InterruptIndex, PICS},
+    memory::vm,
+};
+
+/// Vector of the spurious interrupts of the local APIC, which need no end of interrupt
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Maximum number of I/O APICs handled
+const MAX_IO_APICS: usize = 8;
+
+/// The IA32_APIC_BASE model specific register, with the physical address of the local APIC
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Flag of IA32_APIC_BASE enabling the local APIC
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Registers of the local APIC, as offsets from its base address
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+/// Flag of the spurious interrupt vector register enabling the local APIC
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// Registers of an I/O APIC, the other ones being accessed through these two
+const IOAPIC_REGISTER_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+// Indirect registers of an I/O APIC
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Virtual address of the registers of the local APIC, 0 until it is enabled
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// The I/O APICs, set up by `init`
+static IO_APICS: spin::Mutex<[Option<IoApic>; MAX_IO_APICS]> =
+    spin::Mutex::new([None; MAX_IO_APICS]);
+
+/// An error preventing the use of the APICs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// The CPU has no local APIC
+    NoLocalApic,
+    /// There is no MADT, or `memory::make_global` wasn't called yet
+    NoMadt,
+    /// The MADT lists no I/O APIC
+    NoIoApic,
+    /// The MADT lists more I/O APICs than handled (`MAX_IO_APICS`)
+    TooManyIoApics,
+    /// No I/O APIC handles the global system interrupt of the IRQ
+    UnhandledIrq(u8),
+    /// Mapping the registers of an APIC failed
+    Mapping,
+}
+
+/// An entry of the redirection table of an I/O APIC, telling where an interrupt goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redirection {
+    pub vector: u8,
+    /// APIC ID of the CPU receiving the interrupt
+    pub destination: u8,
+    pub masked: bool,
+    pub level_triggered: bool,
+    pub active_low: bool,
+}
+
+impl Redirection {
+    const ACTIVE_LOW: u64 = 1 << 13;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const MASKED: u64 = 1 << 16;
+
+    fn from_bits(bits: u64) -> Self {
+        Redirection {
+            vector: bits as u8,
+            destination: (bits >> 56) as u8,
+            masked: bits & Self::MASKED != 0,
+            level_triggered: bits & Self::LEVEL_TRIGGERED != 0,
+            active_low: bits & Self::ACTIVE_LOW != 0,
+        }
+    }
+
+    /// Return the bits of the entry, with a fixed delivery mode and a physical destination
+    fn bits(&self) -> u64 {
+        let mut bits = u64::from(self.vector) | (u64::from(self.destination) << 56);
+        if self.masked {
+            bits |= Self::MASKED;
+        }
+        if self.level_triggered {
+            bits |= Self::LEVEL_TRIGGERED;
+        }
+        if self.active_low {
+            bits |= Self::ACTIVE_LOW;
+        }
+        bits
+    }
+}
+
+/// An I/O APIC and the global system interrupts it handles
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    registers: VirtAddr,
+    gsi_base: u32,
+    entry_count: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            write_register(self.registers, IOAPIC_REGISTER_SELECT, register);
+            read_register(self.registers, IOAPIC_WINDOW)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            write_register(self.registers, IOAPIC_REGISTER_SELECT, register);
+            write_register(self.registers, IOAPIC_WINDOW, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entry_count).contains(&gsi)
+    }
+
+    fn redirection(&self, gsi: u32) -> Redirection {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        let bits = u64::from(self.read(register)) | (u64::from(self.read(register + 1)) << 32);
+        Redirection::from_bits(bits)
+    }
+
+    fn set_redirection(&self, gsi: u32, redirection: Redirection) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        let bits = redirection.bits();
+        // masked while it is half written
+        self.write(register, (bits as u32) | Redirection::MASKED as u32);
+        self.write(register + 1, (bits >> 32) as u32);
+        self.write(register, bits as u32);
+    }
+}
+
+/// Read the 32 bits register at `offset` from `base`
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that `base` is the address of mapped
+/// APIC registers.
+unsafe fn read_register(base: VirtAddr, offset: usize) -> u32 {
+    unsafe { ptr::read_volatile((base + offset).as_ptr()) }
+}
+
+/// Write the 32 bits register at `offset` from `base`
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that `base` is the address of mapped
+/// APIC registers.
+unsafe fn write_register(base: VirtAddr, offset: usize, value: u32) {
+    unsafe { ptr::write_volatile((base + offset).as_mut_ptr(), value) }
+}
+
+/// Map the page of registers at `phys`, and return their address
+fn map_registers(phys: PhysAddr) -> Result<VirtAddr, ApicError> {
+    unsafe { vm::map_mmio(phys, Size4KiB::SIZE) }.ok_or(ApicError::Mapping)
+}
+
+/// Map the registers of the I/O APICs listed in `entries` into `io_apics`, check that they handle
+/// the `InterruptIndex` IRQs, then map the registers of the local APIC and return their address.
+///
+/// On error, the I/O APICs mapped so far are left in `io_apics`.
+fn map_apics(
+    madt: &Madt,
+    entries: &[Option<(PhysAddr, u32)>; MAX_IO_APICS],
+    io_apics: &mut [Option<IoApic>; MAX_IO_APICS],
+) -> Result<VirtAddr, ApicError> {
+    for (slot, &(addr, gsi_base)) in io_apics.iter_mut().zip(entries.iter().flatten()) {
+        let mut io_apic = IoApic {
+            registers: map_registers(addr)?,
+            gsi_base,
+            entry_count: 0,
+        };
+        io_apic.entry_count = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        *slot = Some(io_apic);
+    }
+    for index in [InterruptIndex::Timer, InterruptIndex::Keyboard] {
+        let (gsi, _, _) = isa_irq_gsi(madt, index.irq());
+        if !io_apics
+            .iter()
+            .flatten()
+            .any(|io_apic| io_apic.handles(gsi))
+        {
+            return Err(ApicError::UnhandledIrq(index.irq()));
+        }
+    }
+    map_registers(madt.local_apic_addr())
+}
+
+/// Return whether the CPU has a local APIC
+pub fn is_supported() -> bool {
+    let features = unsafe { __cpuid(1) };
+    features.edx & (1 << 9) != 0
+}
+
+/// Return whether the APICs are in use instead of the 8259 PICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Acquire) != 0
+}
+
+/// Return the virtual address of the registers of the local APIC, if enabled
+fn local_apic() -> Option<VirtAddr> {
+    match LOCAL_APIC.load(Ordering::Acquire) {
+        0 => None,
+        addr => Some(VirtAddr::new(addr)),
+    }
+}
+
+/// Return the APIC ID of the current CPU, if the local APIC is enabled
+pub fn local_apic_id() -> Option<u8> {
+    local_apic().map(|base| (unsafe { read_register(base, LAPIC_ID) } >> 24) as u8)
+}
+
+/// Signal the end of the interrupt being handled to the local APIC.
+///
+/// Does nothing if the local APIC isn't enabled.
+pub fn end_of_interrupt() {
+    if let Some(base) = local_apic() {
+        unsafe { write_register(base, LAPIC_EOI, 0) };
+    }
+}
+
+/// Return the global system interrupt, and whether it is level triggered and active low, of the
+/// ISA IRQ `irq`
+fn isa_irq_gsi(madt: &Madt, irq: u8) -> (u32, bool, bool) {
+    let isa = (u32::from(irq), false, false);
+    madt.entries()
+        .find_map(|entry| match entry {
+            MadtEntry::InterruptSourceOverride {
+                irq: source,
+                gsi,
+                flags,
+            } if source == irq => Some((
+                gsi,
+                flags & acpi::MPS_INTI_TRIGGER == acpi::MPS_INTI_LEVEL,
+                flags & acpi::MPS_INTI_POLARITY == acpi::MPS_INTI_ACTIVE_LOW,
+            )),
+            _ => None,
+        })
+        .unwrap_or(isa)
+}
+
+/// Return the current redirection of the ISA IRQ `irq`, if an I/O APIC handles it
+pub fn redirection(irq: u8) -> Option<Redirection> {
+    let madt = Madt::find()?;
+    let (gsi, _, _) = isa_irq_gsi(&madt, irq);
+    let io_apics = without_interrupts(|| *IO_APICS.lock());
+    let io_apic = io_apics
+        .iter()
+        .flatten()
+        .find(|io_apic| io_apic.handles(gsi))?;
+    Some(io_apic.redirection(gsi))
+}
+
+/// Route the ISA IRQ `irq` to `vector` on the current CPU, through the I/O APIC handling it.
+pub fn route_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    let madt = Madt::find().ok_or(ApicError::NoMadt)?;
+    let destination = local_apic_id().ok_or(ApicError::NoLocalApic)?;
+    let (gsi, level_triggered, active_low) = isa_irq_gsi(&madt, irq);
+    let io_apics = without_interrupts(|| *IO_APICS.lock());
+    let io_apic = io_apics
+        .iter()
+        .flatten()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(ApicError::UnhandledIrq(irq))?;
+    io_apic.set_redirection(
+        gsi,
+        Redirection {
+            vector,
+            destination,
+            masked: false,
+            level_triggered,
+            active_low,
+        },
+    );
+    Ok(())
+}
+
+/// Switch from the 8259 PICs to the APICs, routing the IRQs with a handler through them.
+///
+/// The `InterruptIndex` interrupts must be handled by an I/O APIC, while the other IRQs are left
+/// unrouted if none handles them.
+///
+/// On error, the PICs stay in use. Must be called after `memory::make_global`, since the MADT and
+/// the registers of the APICs are read through the global mapper.
+pub fn init() -> Result<(), ApicError> {
+    if !is_supported() {
+        return Err(ApicError::NoLocalApic);
+    }
+    let madt = Madt::find().ok_or(ApicError::NoMadt)?;
+
+    let mut io_apic_entries = [None; MAX_IO_APICS];
+    let mut count = 0;
+    for entry in madt.entries() {
+        if let MadtEntry::IoApic { addr, gsi_base, .. } = entry {
+            let slot = io_apic_entries
+                .get_mut(count)
+                .ok_or(ApicError::TooManyIoApics)?;
+            *slot = Some((addr, gsi_base));
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let mut io_apics = [None; MAX_IO_APICS];
+    let local_apic = match map_apics(&madt, &io_apic_entries, &mut io_apics) {
+        Ok(local_apic) => local_apic,
+        Err(error) => {
+            // give back the registers mapped before the error
+            for io_apic in io_apics.iter().flatten() {
+                unsafe { vm::unmap_mmio(io_apic.registers) };
+            }
+            return Err(error);
+        }
+    };
+
+    without_interrupts(|| {
+        // mask every PIC interrupt, the PICs still being remapped so that spurious ones don't
+        // collide with the CPU exceptions
+        unsafe { PICS.lock().disable() };
+
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        unsafe {
+            apic_base.write(apic_base.read() | APIC_GLOBAL_ENABLE);
+            write_register(local_apic, LAPIC_TASK_PRIORITY, 0);
+            let spurious = read_register(local_apic, LAPIC_SPURIOUS);
+            write_register(
+                local_apic,
+                LAPIC_SPURIOUS,
+                (spurious & !0xff) | LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+            );
+        }
+
+        *IO_APICS.lock() = io_apics;
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::Release);
+
+        // the `InterruptIndex` lines were checked above, and the others stay unhandled if no I/O
+        // APIC has them
+        for line in interrupts::registered_irqs() {
+            let _ = route_irq(line, interrupts::PIC_1_OFFSET + line);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! A nanosecond clock read from the time stamp counter (TSC) of the CPU.
+//!
+//! `init` checks with CPUID that the TSC is invariant (it runs at a constant rate whatever the
+//! power state of the CPU), measures its frequency against the clock of `time` (the HPET, or the
+//! PIT), and from then on `now` is computed from the TSC, which is much cheaper and finer to read
+//! than the timers. Without an invariant TSC, `now` falls back to `time::uptime`.
+//!
+//! `now` continues from `time::uptime` when the TSC is taken into use, so it never goes backwards.
+//!
+//! Main drawbacks:
+//! - the frequency is only as precise as the calibration, which lasts `CALIBRATION`, so `now`
+//!   drifts slowly from `time::uptime`
+//! - the TSC of the other CPUs are assumed to be synchronized with the one of the current CPU
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::time::{self, ClockSource};
+
+/// How long the TSC is measured against the clock of `time`
+pub const CALIBRATION: Duration = Duration::from_millis(50);
+
+/// Nanoseconds in a second
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Highest extended CPUID leaf, queried to know whether the next one exists
+const CPUID_MAX_EXTENDED_LEAF: u32 = 0x8000_0000;
+/// Extended CPUID leaf with the advanced power management features
+const CPUID_POWER_MANAGEMENT_LEAF: u32 = 0x8000_0007;
+
+/// The calibrated TSC once `init` succeeded
+static TSC: spin::Once<Tsc> = spin::Once::new();
+
+/// An error preventing the use of the TSC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClocksourceError {
+    /// The CPU has no TSC
+    NoTsc,
+    /// The TSC may change its rate or stop with the power state of the CPU
+    NotInvariant,
+    /// The TSC or the clock of `time` didn't advance during the calibration
+    Calibration,
+}
+
+/// What `now` is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The calibrated TSC
+    Tsc,
+    /// `time::uptime`
+    Uptime,
+}
+
+/// The TSC, with the values of the TSC and of `time::uptime` when it was taken into use
+#[derive(Debug, Clone, Copy)]
+struct Tsc {
+    frequency_hz: u64,
+    start_tsc: u64,
+    start_nanos: u64,
+}
+
+impl Tsc {
+    fn nanos(&self) -> u64 {
+        let cycles = read_tsc().wrapping_sub(self.start_tsc);
+        let elapsed = u128::from(cycles) * NANOS_PER_SEC / u128::from(self.frequency_hz);
+        self.start_nanos + elapsed as u64
+    }
+}
+
+fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Return whether the CPU has a TSC
+pub fn has_tsc() -> bool {
+    let features = unsafe { __cpuid(1) };
+    features.edx & (1 << 4) != 0
+}
+
+/// Return whether the CPU has a TSC running at a constant rate in every power state
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(CPUID_MAX_EXTENDED_LEAF) }.eax;
+    if max_extended_leaf < CPUID_POWER_MANAGEMENT_LEAF {
+        return false;
+    }
+    let power_management = unsafe { __cpuid(CPUID_POWER_MANAGEMENT_LEAF) };
+    has_tsc() && power_management.edx & (1 << 8) != 0
+}
+
+/// Measure the frequency of the TSC against the clock of `time` for `CALIBRATION`, and return it.
+///
+/// With the PIT and interrupts disabled, the ticks aren't counted, so the calibration relies on
+/// `time::sleep` polling the counter of the PIT instead.
+pub fn calibrate() -> Result<u64, ClocksourceError> {
+    if !has_tsc() {
+        return Err(ClocksourceError::NoTsc);
+    }
+
+    let (cycles, elapsed) = if interrupts::are_enabled() || time::source() == ClockSource::Hpet {
+        // start right when the clock changes, so that its resolution (a tick with the PIT)
+        // doesn't matter much
+        let previous = time::uptime();
+        let mut start = previous;
+        while start == previous {
+            core::hint::spin_loop();
+            start = time::uptime();
+        }
+        let start_tsc = read_tsc();
+        let mut end = start;
+        while end - start < CALIBRATION {
+            core::hint::spin_loop();
+            end = time::uptime();
+        }
+        (read_tsc().wrapping_sub(start_tsc), end - start)
+    } else {
+        let start_tsc = read_tsc();
+        time::sleep(CALIBRATION);
+        (read_tsc().wrapping_sub(start_tsc), CALIBRATION)
+    };
+
+    let frequency_hz = (u128::from(cycles) * NANOS_PER_SEC / elapsed.as_nanos()) as u64;
+    if frequency_hz == 0 {
+        return Err(ClocksourceError::Calibration);
+    }
+    Ok(frequency_hz)
+}
+
+/// Take the TSC into use for `now`, if it is invariant.
+///
+/// On error, `now` keeps returning `time::uptime`. Should be called after `time::init_hpet`, for
+/// the calibration to be precise.
+pub fn init() -> Result<(), ClocksourceError> {
+    if TSC.r#try().is_some() {
+        return Ok(());
+    }
+    if !has_tsc() {
+        return Err(ClocksourceError::NoTsc);
+    }
+    if !is_invariant() {
+        return Err(ClocksourceError::NotInvariant);
+    }
+
+    let frequency_hz = calibrate()?;
+    interrupts::without_interrupts(|| {
+        // continue from the current uptime
+        let start_nanos = time::uptime().as_nanos() as u64;
+        let start_tsc = read_tsc();
+        TSC.call_once(|| Tsc {
+            frequency_hz,
+            start_tsc,
+            start_nanos,
+        });
+    });
+    Ok(())
+}
+
+/// Return what `now` is read from.
+pub fn source() -> Source {
+    match TSC.r#try() {
+        Some(_) => Source::Tsc,
+        None => Source::Uptime,
+    }
+}
+
+/// Return the calibrated frequency of the TSC, if it is in use.
+pub fn frequency_hz() -> Option<u64> {
+    TSC.r#try().map(|tsc| tsc.frequency_hz)
+}
+
+/// Return the time elapsed since `time::init`, in nanoseconds, which never goes backwards.
+pub fn now() -> u64 {
+    match TSC.r#try() {
+        Some(tsc) => tsc.nanos(),
+        None => time::uptime().as_nanos() as u64,
+    }
+}
@@ -1,29 +1,35 @@
+use core::ptr::{addr_of, addr_of_mut};
+
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::stack;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE // grows downwards, so start at the "end"
-        };
-        tss
-    };
-}
+/// Number of pages of the interrupt stacks allocated by `init_stacks`
+pub const IST_STACK_PAGES: u64 = 5;
+
+/// The TSS, whose interrupt stacks are replaced by `init_stacks` once the memory is set up
+///
+/// It is only written to before being loaded or with interrupts disabled, and otherwise only read
+/// by the CPU.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Interrupt stack used until `init_stacks` is called, since allocating stacks needs the memory
+/// to be set up, and faults can happen before that
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // the TSS is a static, so it lives long enough
+        let tss = unsafe { Descriptor::tss_segment_unchecked(addr_of!(TSS)) };
+        let tss_selector = gdt.add_entry(tss);
         (
             gdt,
             Selectors {
@@ -45,9 +51,30 @@ pub fn init() {
         tables::load_tss,
     };
 
+    let boot_stack_start = VirtAddr::from_ptr(unsafe { addr_of!(BOOT_STACK) });
+    // grows downwards, so start at the "end"
+    set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, boot_stack_start + BOOT_STACK_SIZE);
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Replace the boot interrupt stack with stacks from `memory::stack`, which have a guard page.
+///
+/// Must be called after `memory::make_global`.
+pub fn init_stacks() {
+    // the stack is never freed, since it is used until the machine stops
+    let stack =
+        stack::allocate_stack(IST_STACK_PAGES).expect("failed to allocate an interrupt stack");
+    set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, stack.top());
+}
+
+/// Set the stack the CPU switches to for the interrupts using the given IST index.
+fn set_interrupt_stack(index: u16, top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[usize::from(index)] = top;
+    });
+}
@@ -1,9 +1,14 @@
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
 
-use crate::{gdt, print, println, hlt_loop};
+use crate::{apic, print, time};
+
+pub mod exceptions;
 
 // *************
 // * IDT setup *
@@ -14,17 +19,15 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
 
         // CPU exceptions
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe { // double fault (with custom stack)
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        exceptions::set_handlers(&mut idt);
+
+        // IRQs, dispatched to the handlers registered with `register_irq`
+        for (line, stub) in IRQ_STUBS.into_iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + line].set_handler_fn(stub);
         }
 
-        // PIC interrupts
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // APIC interrupts
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
@@ -54,25 +57,133 @@ pub enum InterruptIndex {
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
+    pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// Return the ISA IRQ line of the interrupt
+    pub const fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
+// ****************
+// * IRQ handlers *
+// ****************
+
+/// Number of IRQ lines, those of the two PICs
+pub const IRQ_LINES: usize = 16;
+
+/// A handler of an IRQ, called with interrupts disabled
+pub type IrqHandler = fn();
+
+/// An error registering an IRQ handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line isn't below `IRQ_LINES`
+    InvalidLine(u8),
+    /// The line already has a handler
+    AlreadyRegistered(u8),
+}
+
+/// The handler of every IRQ line, the timer and keyboard ones being there from the start
+static IRQ_HANDLERS: spin::Mutex<[Option<IrqHandler>; IRQ_LINES]> = spin::Mutex::new({
+    let mut handlers: [Option<IrqHandler>; IRQ_LINES] = [None; IRQ_LINES];
+    handlers[InterruptIndex::Timer.irq() as usize] = Some(timer_interrupt_handler);
+    handlers[InterruptIndex::Keyboard.irq() as usize] = Some(keyboard_interrupt_handler);
+    handlers
+});
+
+/// The entry point of every IRQ line, at vector `PIC_1_OFFSET + line`
+const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [
+    irq_stub::<0>,
+    irq_stub::<1>,
+    irq_stub::<2>,
+    irq_stub::<3>,
+    irq_stub::<4>,
+    irq_stub::<5>,
+    irq_stub::<6>,
+    irq_stub::<7>,
+    irq_stub::<8>,
+    irq_stub::<9>,
+    irq_stub::<10>,
+    irq_stub::<11>,
+    irq_stub::<12>,
+    irq_stub::<13>,
+    irq_stub::<14>,
+    irq_stub::<15>,
+];
+
+/// Call the handler registered for the IRQ `LINE`, if any, and signal the end of the interrupt.
+extern "x86-interrupt" fn irq_stub<const LINE: u8>(_stack_frame: InterruptStackFrame) {
+    // interrupts are disabled here, and while the handlers are changed
+    let handler = IRQ_HANDLERS.lock()[usize::from(LINE)];
+    if let Some(handler) = handler {
+        handler();
+    }
+    notify_end_of_irq(LINE);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+/// Signal the end of the IRQ `line` to the interrupt controller in use: the local APIC once
+/// `apic::init` succeeded, the PICs otherwise.
+fn notify_end_of_irq(line: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + line) };
+    }
+}
+
+/// Make `handler` handle the IRQ `line`, and route the line to it if the APICs are in use.
+///
+/// The end of the interrupt is signaled once the handler returns, so it mustn't do it itself.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if usize::from(line) >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(line));
+    }
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = &mut handlers[usize::from(line)];
+        if slot.is_some() {
+            return Err(IrqError::AlreadyRegistered(line));
+        }
+        *slot = Some(handler);
+        Ok(())
+    })?;
+
+    if apic::is_enabled() {
+        // the line stays unhandled if no I/O APIC has it, like with the PICs without a device
+        let _ = apic::route_irq(line, PIC_1_OFFSET + line);
+    }
+    Ok(())
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// Remove the handler of the IRQ `line`, and return it.
+///
+/// The line stays routed, its interrupts being acknowledged without calling anything.
+pub fn unregister_irq(line: u8) -> Option<IrqHandler> {
+    let line = usize::from(line);
+    if line >= IRQ_LINES {
+        return None;
     }
+    without_interrupts(|| IRQ_HANDLERS.lock()[line].take())
+}
+
+/// Return the IRQ lines that have a handler
+pub fn registered_irqs() -> impl Iterator<Item = u8> {
+    let handlers = without_interrupts(|| *IRQ_HANDLERS.lock());
+    (0..IRQ_LINES as u8).filter(move |&line| handlers[usize::from(line)].is_some())
+}
+
+fn timer_interrupt_handler() {
+    time::tick();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt_handler() {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -95,40 +206,10 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
             }
         }
     }
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
-}
-
-// ************************
-// * CPU exceptions setup *
-// ************************
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed address: {:?}", Cr2::read());
-    println!("Error code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
+/// Spurious interrupts of the local APIC, which must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,388 @@
+//! Handlers of the CPU exceptions, reporting what caused them.
+//!
+//! Every architectural exception has a handler, so that none escalates to a double fault for lack
+//! of one. Each handler reports its exception to VGA and serial: its name and vector, its decoded
+//! error code (like the segment selector of a #GP or the kind of access of a #PF), the state
+//! describing it further (like the faulting address of a #PF), and the interrupt stack frame. The
+//! last exception reported is kept for inspection, see `last_exception`.
+//!
+//! The traps (#DB, #BP) and NMIs resume execution after the report, while the faults and aborts
+//! panic, unless they are page faults resolved by `memory`.
+//!
+//! Main drawbacks:
+//! - reporting to VGA deadlocks if the exception is raised while the VGA writer is locked
+//! - alignment checks are only raised in user mode, which the kernel doesn't run yet
+
+use core::{arch::asm, fmt};
+
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{
+        DescriptorTable, ExceptionVector, InterruptDescriptorTable, InterruptStackFrame,
+        InterruptStackFrameValue, PageFaultErrorCode, SelectorErrorCode,
+    },
+    VirtAddr,
+};
+
+use crate::{gdt, memory, println, serial_println};
+
+/// The last exception reported
+static LAST_EXCEPTION: spin::Mutex<Option<Exception>> = spin::Mutex::new(None);
+
+/// An exception raised by the CPU
+#[derive(Debug, Clone, Copy)]
+pub struct Exception {
+    pub vector: ExceptionVector,
+    /// The error code pushed by the CPU, for the exceptions that have one
+    pub error_code: Option<u64>,
+    pub details: Details,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+/// State describing an exception further, read by its handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Details {
+    None,
+    /// The address accessed by a #PF, from CR2
+    FaultAddress(VirtAddr),
+    /// The status word of the x87 FPU, for a #MF
+    X87Status(u16),
+    /// The MXCSR register, for a #XM
+    Mxcsr(u32),
+}
+
+impl Exception {
+    /// Return the name of the exception, like "GENERAL PROTECTION FAULT"
+    pub fn name(&self) -> &'static str {
+        match self.vector {
+            ExceptionVector::Division => "DIVIDE ERROR",
+            ExceptionVector::Debug => "DEBUG",
+            ExceptionVector::NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            ExceptionVector::Breakpoint => "BREAKPOINT",
+            ExceptionVector::Overflow => "OVERFLOW",
+            ExceptionVector::BoundRange => "BOUND RANGE EXCEEDED",
+            ExceptionVector::InvalidOpcode => "INVALID OPCODE",
+            ExceptionVector::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            ExceptionVector::Double => "DOUBLE FAULT",
+            ExceptionVector::InvalidTss => "INVALID TSS",
+            ExceptionVector::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            ExceptionVector::Stack => "STACK-SEGMENT FAULT",
+            ExceptionVector::GeneralProtection => "GENERAL PROTECTION FAULT",
+            ExceptionVector::Page => "PAGE FAULT",
+            ExceptionVector::X87FloatingPoint => "X87 FLOATING-POINT EXCEPTION",
+            ExceptionVector::AlignmentCheck => "ALIGNMENT CHECK",
+            ExceptionVector::MachineCheck => "MACHINE CHECK",
+            ExceptionVector::SimdFloatingPoint => "SIMD FLOATING-POINT EXCEPTION",
+            ExceptionVector::Virtualization => "VIRTUALIZATION EXCEPTION",
+            ExceptionVector::ControlProtection => "CONTROL PROTECTION EXCEPTION",
+            ExceptionVector::HypervisorInjection => "HYPERVISOR INJECTION EXCEPTION",
+            ExceptionVector::VmmCommunication => "VMM COMMUNICATION EXCEPTION",
+            ExceptionVector::Security => "SECURITY EXCEPTION",
+            _ => "UNKNOWN EXCEPTION",
+        }
+    }
+
+    /// Return the mnemonic of the exception, like "#GP"
+    pub fn mnemonic(&self) -> &'static str {
+        match self.vector {
+            ExceptionVector::Division => "#DE",
+            ExceptionVector::Debug => "#DB",
+            ExceptionVector::NonMaskableInterrupt => "NMI",
+            ExceptionVector::Breakpoint => "#BP",
+            ExceptionVector::Overflow => "#OF",
+            ExceptionVector::BoundRange => "#BR",
+            ExceptionVector::InvalidOpcode => "#UD",
+            ExceptionVector::DeviceNotAvailable => "#NM",
+            ExceptionVector::Double => "#DF",
+            ExceptionVector::InvalidTss => "#TS",
+            ExceptionVector::SegmentNotPresent => "#NP",
+            ExceptionVector::Stack => "#SS",
+            ExceptionVector::GeneralProtection => "#GP",
+            ExceptionVector::Page => "#PF",
+            ExceptionVector::X87FloatingPoint => "#MF",
+            ExceptionVector::AlignmentCheck => "#AC",
+            ExceptionVector::MachineCheck => "#MC",
+            ExceptionVector::SimdFloatingPoint => "#XM",
+            ExceptionVector::Virtualization => "#VE",
+            ExceptionVector::ControlProtection => "#CP",
+            ExceptionVector::HypervisorInjection => "#HV",
+            ExceptionVector::VmmCommunication => "#VC",
+            ExceptionVector::Security => "#SX",
+            _ => "#??",
+        }
+    }
+
+    /// Return the error code as a segment selector, for the exceptions whose error code is one
+    /// (#TS, #NP, #SS and #GP), unless it is 0 (the exception isn't related to a segment)
+    pub fn selector(&self) -> Option<SelectorErrorCode> {
+        match self.vector {
+            ExceptionVector::InvalidTss
+            | ExceptionVector::SegmentNotPresent
+            | ExceptionVector::Stack
+            | ExceptionVector::GeneralProtection => {
+                let selector = SelectorErrorCode::new_truncate(self.error_code?);
+                (!selector.is_null()).then_some(selector)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            self.name(),
+            self.mnemonic(),
+            self.vector as u8
+        )?;
+        if let Some(error_code) = self.error_code {
+            write!(f, "Error code: {:#x}", error_code)?;
+            if let Some(selector) = self.selector() {
+                let table = match selector.descriptor_table() {
+                    DescriptorTable::Gdt => "GDT",
+                    DescriptorTable::Idt => "IDT",
+                    DescriptorTable::Ldt => "LDT",
+                };
+                write!(f, " (selector index {} in the {}", selector.index(), table)?;
+                if selector.external() {
+                    write!(f, ", external event")?;
+                }
+                write!(f, ")")?;
+            } else {
+                match self.vector {
+                    ExceptionVector::InvalidTss
+                    | ExceptionVector::SegmentNotPresent
+                    | ExceptionVector::Stack
+                    | ExceptionVector::GeneralProtection => write!(f, " (not segment related)")?,
+                    ExceptionVector::Page => write!(
+                        f,
+                        " ({:?})",
+                        PageFaultErrorCode::from_bits_truncate(error_code)
+                    )?,
+                    ExceptionVector::ControlProtection => {
+                        let cause = match error_code & 0x7fff {
+                            1 => "near return",
+                            2 => "far return or interrupt return",
+                            3 => "missing end branch",
+                            4 => "shadow stack restore",
+                            5 => "shadow stack busy",
+                            _ => "unknown cause",
+                        };
+                        write!(f, " ({})", cause)?;
+                    }
+                    _ => {}
+                }
+            }
+            writeln!(f)?;
+        }
+        match self.details {
+            Details::None => {}
+            Details::FaultAddress(addr) => writeln!(f, "Accessed address: {:?}", addr)?,
+            Details::X87Status(status) => writeln!(f, "x87 status word: {:#06x}", status)?,
+            Details::Mxcsr(mxcsr) => writeln!(f, "MXCSR: {:#010x}", mxcsr)?,
+        }
+        write!(f, "{:#?}", self.stack_frame)
+    }
+}
+
+/// Return the last exception reported by a handler, if any.
+pub fn last_exception() -> Option<Exception> {
+    *LAST_EXCEPTION.lock()
+}
+
+/// Install the handler of every CPU exception in `idt`.
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        // double fault (with custom stack)
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception
+        .set_handler_fn(hypervisor_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_handler);
+}
+
+/// Record `exception` as the last one and print it to VGA and serial
+fn report(exception: Exception) {
+    // the exception may have been raised while the last one was read
+    if let Some(mut last) = LAST_EXCEPTION.try_lock() {
+        *last = Some(exception);
+    }
+    println!("{}", exception);
+    serial_println!("{}", exception);
+}
+
+/// Report an exception and panic, for the exceptions the kernel can't recover from
+fn fatal(
+    vector: ExceptionVector,
+    error_code: Option<u64>,
+    details: Details,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    let exception = Exception {
+        vector,
+        error_code,
+        details,
+        stack_frame: **stack_frame,
+    };
+    report(exception);
+    panic!("EXCEPTION: {}", exception.name());
+}
+
+/// Define the handler of a fatal exception, with or without an error code
+macro_rules! fatal_handler {
+    ($handler:ident, $vector:ident) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            fatal(ExceptionVector::$vector, None, Details::None, &stack_frame);
+        }
+    };
+    ($handler:ident, $vector:ident, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            fatal(
+                ExceptionVector::$vector,
+                Some(error_code),
+                Details::None,
+                &stack_frame,
+            );
+        }
+    };
+}
+
+fatal_handler!(divide_error_handler, Division);
+fatal_handler!(overflow_handler, Overflow);
+fatal_handler!(bound_range_exceeded_handler, BoundRange);
+fatal_handler!(invalid_opcode_handler, InvalidOpcode);
+fatal_handler!(device_not_available_handler, DeviceNotAvailable);
+fatal_handler!(invalid_tss_handler, InvalidTss, error_code);
+fatal_handler!(segment_not_present_handler, SegmentNotPresent, error_code);
+fatal_handler!(stack_segment_fault_handler, Stack, error_code);
+fatal_handler!(
+    general_protection_fault_handler,
+    GeneralProtection,
+    error_code
+);
+fatal_handler!(alignment_check_handler, AlignmentCheck, error_code);
+fatal_handler!(virtualization_handler, Virtualization);
+fatal_handler!(control_protection_handler, ControlProtection, error_code);
+fatal_handler!(hypervisor_injection_handler, HypervisorInjection);
+fatal_handler!(vmm_communication_handler, VmmCommunication, error_code);
+fatal_handler!(security_handler, Security, error_code);
+
+/// Report an exception after which execution resumes
+fn report_and_resume(vector: ExceptionVector, stack_frame: &InterruptStackFrame) {
+    report(Exception {
+        vector,
+        error_code: None,
+        details: Details::None,
+        stack_frame: **stack_frame,
+    });
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    report_and_resume(ExceptionVector::Debug, &stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    report_and_resume(ExceptionVector::NonMaskableInterrupt, &stack_frame);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    report_and_resume(ExceptionVector::Breakpoint, &stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    fatal(
+        ExceptionVector::Double,
+        Some(error_code),
+        Details::None,
+        &stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    // accesses to lazily-backed memory are retried once the page is mapped, and writes to
+    // copy-on-write pages once the page is writable
+    let addr = Cr2::read();
+    if memory::vm::resolve_page_fault(addr, error_code)
+        || memory::cow::resolve_page_fault(addr, error_code)
+    {
+        return;
+    }
+
+    fatal(
+        ExceptionVector::Page,
+        Some(error_code.bits()),
+        Details::FaultAddress(addr),
+        &stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let status: u16;
+    unsafe { asm!("fnstsw ax", out("ax") status, options(nomem, nostack)) };
+    fatal(
+        ExceptionVector::X87FloatingPoint,
+        None,
+        Details::X87Status(status),
+        &stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    fatal(
+        ExceptionVector::MachineCheck,
+        None,
+        Details::None,
+        &stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let mut mxcsr: u32 = 0;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack)) };
+    fatal(
+        ExceptionVector::SimdFloatingPoint,
+        None,
+        Details::Mxcsr(mxcsr),
+        &stack_frame,
+    );
+}
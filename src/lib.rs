@@ -15,6 +15,9 @@
 // Allow mutable references in const functions
 #![feature(const_mut_refs)]
 
+// Report the heap state when an infallible allocation fails
+#![feature(alloc_error_handler)]
+
 use core::panic::PanicInfo;
 
 #[cfg(test)]
@@ -22,17 +25,30 @@ use bootloader::{BootInfo, entry_point};
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod clocksource;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod serial;
+pub mod time;
 pub mod vga_buffer;
 
+/// Called when an infallible allocation (e.g. `Box::new`) fails: report it and panic, which goes
+/// through `test_panic_handler` in tests
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    allocator::report_out_of_memory(layout);
+    panic!("allocation error: {:?}", layout)
+}
+
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
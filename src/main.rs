@@ -12,7 +12,7 @@ use core::panic::PanicInfo;
 use bootloader::{entry_point, BootInfo};
 use x86_64::VirtAddr;
 
-use clacos::{allocator, memory};
+use clacos::{allocator, apic, clocksource, gdt, memory, time};
 
 mod serial;
 mod vga_buffer;
@@ -27,11 +27,31 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // bootloader
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(physical_memory_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe {
+        memory::BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
 
-    // Set up the heap
+    // Set up the heap, then hand the mapper and frame allocator over so that it can grow later
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    // now that stacks can be allocated, give the interrupt stacks a guard page
+    gdt::init_stacks();
+
+    // switch from the legacy PICs to the APICs, which need the memory mapping too
+    if let Err(error) = apic::init() {
+        println!(
+            "APIC initialization failed, keeping the 8259 PICs: {:?}",
+            error
+        );
+    }
+    // the HPET is found and mapped through the memory mapping as well
+    if let Err(error) = time::init_hpet() {
+        println!("HPET initialization failed, keeping the PIT: {:?}", error);
+    }
+    if let Err(error) = clocksource::init() {
+        println!("TSC not usable, keeping the timer as clock: {:?}", error);
+    }
 
     #[cfg(test)]
     test_main();
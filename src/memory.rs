@@ -1,9 +1,27 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+pub mod address_space;
+pub mod cow;
+pub mod dump;
+pub mod protection;
+pub mod stack;
+pub mod vm;
+
+/// The kernel's page table mapper and frame allocator, once handed over with `make_global`.
+static GLOBAL_PAGING: spin::Mutex<Option<GlobalPaging>> = spin::Mutex::new(None);
+
+struct GlobalPaging {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BitmapFrameAllocator,
+}
+
 /// Return a mutable reference to the active level 4 table.
 ///
 /// # Safety
@@ -25,6 +43,9 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 
 /// Initialize a new OffsetPageTable.
 ///
+/// This also records what the bootloader mapped in the kernel address space (see `vm`), and
+/// enforces W^X on the kernel mappings (see `protection`).
+///
 /// # Safety
 ///
 /// This function is unsafe because the caller must guarantee that the complete physical memory is
@@ -32,10 +53,127 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 /// only called once to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = unsafe { active_level_4_table(physical_memory_offset) };
-    unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) }
+    vm::init(level_4_table, physical_memory_offset);
+    let mut mapper = unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) };
+    protection::enforce_wx(&mut mapper);
+    mapper
+}
+
+/// Hand the kernel's mapper and frame allocator over to the memory subsystem.
+///
+/// This makes them available through `with_global_paging` to code that runs after boot and can't
+/// be given references to them, like the heap allocator when it needs to grow.
+pub fn make_global(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    *GLOBAL_PAGING.lock() = Some(GlobalPaging {
+        mapper,
+        frame_allocator,
+    });
+}
+
+/// Run `f` with the global mapper and frame allocator, with interrupts disabled.
+///
+/// Return None without calling `f` if `make_global` wasn't called yet.
+///
+/// `f` must not allocate heap memory, since growing the heap needs them too.
+pub fn with_global_paging<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        GLOBAL_PAGING
+            .lock()
+            .as_mut()
+            .map(|paging| f(&mut paging.mapper, &mut paging.frame_allocator))
+    })
+}
+
+/// Like `with_global_paging`, but return None instead of waiting when they are already in use,
+/// e.g. when called from an exception raised while they are.
+fn try_with_global_paging<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        GLOBAL_PAGING
+            .try_lock()?
+            .as_mut()
+            .map(|paging| f(&mut paging.mapper, &mut paging.frame_allocator))
+    })
+}
+
+/// Map the `size` bytes of virtual memory at `start` (both page aligned) to newly allocated frames.
+///
+/// The parts of the range that are aligned to 2 MiB are mapped with huge pages, unless the frame
+/// allocator runs out of huge frames, in which case they are mapped with 4 KiB pages too.
+///
+/// On error, the pages mapped until then stay mapped.
+pub fn map_range<M, A>(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    let mut mapped = 0;
+    while mapped < size {
+        mapped += map_next_page(
+            start + mapped,
+            size - mapped,
+            flags,
+            mapper,
+            frame_allocator,
+        )?;
+    }
+    Ok(())
+}
+
+/// Map the page at `addr` to a newly allocated frame, using a huge page if `addr` is aligned for
+/// one, it fits in `max_size` bytes and there is a huge frame available.
+///
+/// Return the size of the mapped page. This is the building block of `map_range`, for callers
+/// that want to keep track of partial progress.
+pub fn map_next_page<M, A>(
+    addr: VirtAddr,
+    max_size: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    if addr.is_aligned(Size2MiB::SIZE) && max_size >= Size2MiB::SIZE {
+        let frame: Option<PhysFrame<Size2MiB>> = frame_allocator.allocate_frame();
+        if let Some(frame) = frame {
+            let page = Page::<Size2MiB>::containing_address(addr);
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+                .map_err(|error| match error {
+                    MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+                    MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+                    MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(
+                        PhysFrame::containing_address(frame.start_address()),
+                    ),
+                })?
+                .flush();
+            return Ok(Size2MiB::SIZE);
+        }
+    }
+
+    let frame: PhysFrame<Size4KiB> = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    Ok(Size4KiB::SIZE)
 }
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map
+///
+/// It can't free frames, and finding the next frame walks the memory map from the start: see
+/// `BitmapFrameAllocator` for the allocator used once the kernel is initialized.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -80,3 +218,237 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+/// A FrameAllocator (and FrameDeallocator) keeping track of the state of every frame in a bitmap.
+///
+/// The bitmap has one bit per frame up to the last usable one, set when the frame is in use or
+/// not usable at all. It is stored in the first usable frames large enough to hold it, accessed
+/// through the physical memory mapping.
+///
+/// Frames can be shared (e.g. by copy-on-write mappings): each frame has a count of the references
+/// to it besides the first one, stored after the bitmap, and deallocating a shared frame only
+/// drops a reference.
+///
+/// It hands out 2 MiB frames too, made of 512 free 4 KiB frames (8 words of the bitmap) starting
+/// at a 2 MiB aligned address.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Number of additional references to each frame
+    shares: &'static mut [u16],
+    /// Number of usable frames, whether in use or not
+    total: usize,
+    /// Number of usable frames in use, including those holding the bitmap
+    used: usize,
+    /// Index of a word of the bitmap that may have a free frame, all the previous ones are full
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a BitmapFrameAllocator from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed memory map is
+    /// valid, with all frames that are marked as `USABLE` in it really unused, and that the
+    /// complete physical memory is mapped to virtual memory at the passed
+    /// `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| r.range.start_frame_number as usize..r.range.end_frame_number as usize)
+        };
+        let frame_count = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        let words = (frame_count + 63) / 64;
+        let metadata_size = words * 8 + frame_count * 2;
+        let bitmap_frames = (metadata_size + Size4KiB::SIZE as usize - 1) / Size4KiB::SIZE as usize;
+
+        // put the bitmap, then the reference counts, at the start of the first usable region large
+        // enough for them
+        let bitmap_start = usable_regions()
+            .find(|r| r.len() >= bitmap_frames)
+            .expect("no usable region large enough for the frame bitmap")
+            .start;
+        let (bitmap, shares) = unsafe {
+            let virt = physical_memory_offset + bitmap_start as u64 * Size4KiB::SIZE;
+            (
+                core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u64>(), words),
+                core::slice::from_raw_parts_mut(
+                    (virt + words * 8).as_mut_ptr::<u16>(),
+                    frame_count,
+                ),
+            )
+        };
+
+        // everything is unavailable, except for the usable regions
+        bitmap.fill(u64::MAX);
+        shares.fill(0);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            total: 0,
+            used: 0,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            for frame in region {
+                allocator.set_used(frame, false);
+                allocator.total += 1;
+            }
+        }
+        for frame in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.set_used(frame, true);
+            allocator.used += 1;
+        }
+        allocator
+    }
+
+    /// Number of usable frames, whether in use or not
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    /// Number of usable frames in use
+    pub fn used_frames(&self) -> usize {
+        self.used
+    }
+
+    /// Number of frames that can still be allocated
+    pub fn free_frames(&self) -> usize {
+        self.total - self.used
+    }
+
+    /// Add a reference to a frame in use, which then needs one more deallocation to be freed.
+    ///
+    /// Panics if the frame is not in use, or has too many references already.
+    pub fn add_reference(&mut self, frame: PhysFrame) {
+        let index = Self::frame_index(frame);
+        assert!(
+            index < self.shares.len() && self.is_used(index),
+            "frame {:#x} is not in use",
+            frame.start_address()
+        );
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("too many references to a frame");
+    }
+
+    /// Number of references to a frame, 0 if it is free
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        let index = Self::frame_index(frame);
+        if index < self.shares.len() && self.is_used(index) {
+            1 + usize::from(self.shares[index])
+        } else {
+            0
+        }
+    }
+
+    fn frame_index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / Size4KiB::SIZE) as usize
+    }
+
+    /// Number of bitmap words covering a 2 MiB frame
+    const HUGE_FRAME_WORDS: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize / 64;
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if used {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+        } else {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // skip the full words, 64 frames at a time
+        let word = (self.next_word..self.bitmap.len()).find(|&w| self.bitmap[w] != u64::MAX)?;
+        self.next_word = word;
+        let frame = word * 64 + self.bitmap[word].trailing_ones() as usize;
+        self.set_used(frame, true);
+        self.used += 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame as u64 * Size4KiB::SIZE,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Drop a reference to the frame, giving it back with the last one so that it can be allocated
+    /// again.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the frame was allocated by this allocator and is not used
+    /// anymore through this reference. Freeing a frame that is not in use panics.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let frame = Self::frame_index(frame);
+        assert!(
+            frame / 64 < self.bitmap.len() && self.is_used(frame),
+            "frame {:#x} is not in use",
+            frame as u64 * Size4KiB::SIZE
+        );
+        if let Some(shares) = self.shares.get_mut(frame).filter(|shares| **shares > 0) {
+            *shares -= 1;
+            return;
+        }
+        self.set_used(frame, false);
+        self.used -= 1;
+        self.next_word = self.next_word.min(frame / 64);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        // the words before `next_word` are full, so are the huge frames covering them
+        let first = self.next_word / Self::HUGE_FRAME_WORDS * Self::HUGE_FRAME_WORDS;
+        let word = (first..self.bitmap.len())
+            .step_by(Self::HUGE_FRAME_WORDS)
+            .find(|&w| {
+                let words = self.bitmap.get(w..w + Self::HUGE_FRAME_WORDS);
+                words.map_or(false, |words| words.iter().all(|&word| word == 0))
+            })?;
+        self.bitmap[word..word + Self::HUGE_FRAME_WORDS].fill(u64::MAX);
+        self.used += Self::HUGE_FRAME_WORDS * 64;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            (word * 64) as u64 * Size4KiB::SIZE,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    /// Give the huge frame back, so that it can be allocated again (as a whole or as 4 KiB
+    /// frames).
+    ///
+    /// # Safety
+    ///
+    /// Same as for 4 KiB frames. Freeing a frame that is not entirely in use, or one of whose 4 KiB
+    /// frames is still shared (see `add_reference`), panics.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let word = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize / 64;
+        let frames = word * 64..(word + Self::HUGE_FRAME_WORDS) * 64;
+        assert!(
+            self.shares
+                .get(frames.start..frames.end.min(self.shares.len()))
+                .map_or(true, |shares| shares.iter().all(|&shares| shares == 0)),
+            "frame {:#x} is still shared",
+            frame.start_address()
+        );
+        let words = self.bitmap.get_mut(word..word + Self::HUGE_FRAME_WORDS);
+        assert!(
+            words
+                .as_ref()
+                .map_or(false, |words| words.iter().all(|&w| w == u64::MAX)),
+            "frame {:#x} is not in use",
+            frame.start_address()
+        );
+        words.unwrap().fill(0);
+        self.used -= Self::HUGE_FRAME_WORDS * 64;
+        self.next_word = self.next_word.min(word);
+    }
+}
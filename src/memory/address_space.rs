@@ -0,0 +1,237 @@
+//! Address spaces with page tables of their own, e.g. for user processes.
+//!
+//! An address space shares the kernel mappings with every other one: its level 4 table is a copy
+//! of the kernel entries of the active one, pointing to the same level 3 tables. The kernel
+//! entries are those of the kernel half of the address space, and those of the lower half where
+//! the bootloader mapped something (like the kernel image), as recorded by `vm`. The other entries
+//! of the lower half are for user pages, which belong to the address space.
+//!
+//! Main drawbacks:
+//! - a kernel mapping added under a level 4 entry that wasn't present when an address space was
+//!   created isn't seen from that address space
+//! - page faults in user pages aren't resolved by demand paging nor copy-on-write, which only use
+//!   the page tables of the global mapper
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    vm::{self, RegionKind},
+    BitmapFrameAllocator,
+};
+
+/// An address space: a level 4 page table and the user pages mapped in it
+pub struct AddressSpace {
+    mapper: OffsetPageTable<'static>,
+    level_4_frame: PhysFrame,
+}
+
+/// Return which level 4 entries hold kernel mappings
+fn kernel_entries() -> [bool; 512] {
+    let mut kernel = [false; 512];
+    kernel[256..].fill(true);
+    vm::for_each_region(|region| {
+        let kind = region.kind();
+        if kind == RegionKind::Bootloader || kind == RegionKind::PhysicalMemory {
+            kernel[usize::from(region.start().p4_index())] = true;
+        }
+    });
+    kernel
+}
+
+/// Return whether `page` is in the user part of every address space
+pub fn is_user_page(page: Page) -> bool {
+    !kernel_entries()[usize::from(page.p4_index())]
+}
+
+/// Return the table at the given frame, through the physical memory mapping
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the frame holds a page table,
+/// and that it isn't referenced elsewhere while the returned reference is used.
+unsafe fn table_at(phys_offset: VirtAddr, frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *(phys_offset + frame.start_address().as_u64()).as_mut_ptr() }
+}
+
+impl AddressSpace {
+    /// Create an address space sharing the kernel mappings of the active one, without any user
+    /// page.
+    ///
+    /// Return None if `memory::make_global` wasn't called yet or if there is no frame left.
+    pub fn new() -> Option<Self> {
+        super::with_global_paging(|mapper, frame_allocator| {
+            let phys_offset = mapper.phys_offset();
+            let level_4_frame: PhysFrame = frame_allocator.allocate_frame()?;
+            let (active_frame, _) = Cr3::read();
+
+            let table = unsafe { table_at(phys_offset, level_4_frame) };
+            table.zero();
+            let active = unsafe { table_at(phys_offset, active_frame) };
+            for (index, kernel) in kernel_entries().into_iter().enumerate() {
+                if kernel {
+                    table[index] = active[index].clone();
+                }
+            }
+
+            Some(AddressSpace {
+                mapper: unsafe { OffsetPageTable::new(table, phys_offset) },
+                level_4_frame,
+            })
+        })
+        .flatten()
+    }
+
+    /// Return the mapper of the page tables of the address space.
+    ///
+    /// It must not be used to map 1 GiB pages in the user part: the frame allocator has no such
+    /// frames, so `Drop` has nothing to give them back to and leaves them alone.
+    pub fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
+        &mut self.mapper
+    }
+
+    /// Return the frame of the level 4 table of the address space
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Return whether the address space is the active one
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Map the user page `page` to a newly allocated zeroed frame, with `flags` and
+    /// `USER_ACCESSIBLE`.
+    ///
+    /// Panics if the page isn't in the user part of the address space.
+    pub fn map_user(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_page(page), "{:?} isn't a user page", page);
+        let mapper = &mut self.mapper;
+        super::with_global_paging(|_, frame_allocator| {
+            let frame: PhysFrame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let frame_ptr: *mut u8 =
+                (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+            unsafe { frame_ptr.write_bytes(0, Size4KiB::SIZE as usize) };
+
+            let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    // the page isn't in the TLB unless the address space is active
+                    flush.flush();
+                    Ok(())
+                }
+                Err(error) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(error)
+                }
+            }
+        })
+        .ok_or(MapToError::FrameAllocationFailed)?
+    }
+
+    /// Make this address space the active one, by writing its level 4 table to CR3.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that nothing used by the kernel
+    /// lives in the user pages of the previous address space, e.g. the current stack.
+    pub unsafe fn switch_to(&self) {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(self.level_4_frame, flags) };
+    }
+
+    /// Make the kernel address space (the one of the global mapper) the active one again.
+    ///
+    /// # Safety
+    ///
+    /// Same as `switch_to`.
+    pub unsafe fn switch_to_kernel() {
+        let kernel_frame = super::with_global_paging(|mapper, _| {
+            let virt = VirtAddr::from_ptr(mapper.level_4_table() as *const PageTable);
+            PhysFrame::containing_address(PhysAddr::new(virt - mapper.phys_offset()))
+        })
+        .expect("no kernel address space");
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(kernel_frame, flags) };
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Drop a reference to every frame mapped in the user part (freeing the frames mapped with
+    /// `map_user`), and free the page tables of the user part and the level 4 table.
+    ///
+    /// Panics if the address space is the active one.
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let phys_offset = self.mapper.phys_offset();
+        super::with_global_paging(|_, frame_allocator| {
+            let table = self.mapper.level_4_table();
+            for (entry, kernel) in table.iter().zip(kernel_entries()) {
+                if !kernel && entry.flags().contains(PageTableFlags::PRESENT) {
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    unsafe { free_table(phys_offset, frame, 3, frame_allocator) };
+                }
+            }
+            unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+        });
+    }
+}
+
+/// Free the table of the given level at `frame`, its subtables, and drop a reference to the
+/// frames they map.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the table isn't used anymore.
+unsafe fn free_table(
+    phys_offset: VirtAddr,
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut BitmapFrameAllocator,
+) {
+    let table = unsafe { table_at(phys_offset, frame) };
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let addr = entry.addr();
+        debug_assert!(
+            !(level == 3 && flags.contains(PageTableFlags::HUGE_PAGE)),
+            "1 GiB user page mapped to {:#x}",
+            addr
+        );
+        match level {
+            1 => unsafe {
+                frame_allocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(addr))
+            },
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => unsafe {
+                frame_allocator.deallocate_frame(PhysFrame::<Size2MiB>::containing_address(addr))
+            },
+            // not a table, and not from the frame allocator either (see `AddressSpace::mapper`)
+            3 if flags.contains(PageTableFlags::HUGE_PAGE) => {}
+            2..=4 => unsafe {
+                free_table(
+                    phys_offset,
+                    PhysFrame::containing_address(addr),
+                    level - 1,
+                    frame_allocator,
+                )
+            },
+            _ => unreachable!("no page table of level {}", level),
+        }
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
@@ -0,0 +1,139 @@
+//! Copy-on-write mappings, sharing a frame between several pages until one of them writes to it.
+//!
+//! A copy-on-write page is mapped read-only, with the `COPY_ON_WRITE` flag (an entry bit available
+//! to the OS) telling that it may be made writable, and holds a reference to its frame (see
+//! `BitmapFrameAllocator::add_reference`). The first write to it faults, and `resolve_page_fault`
+//! then gives the page a copy of the frame of its own, or the frame itself if it is the last
+//! reference to it.
+//!
+//! Main drawbacks:
+//! - only 4 KiB pages can be shared
+//! - faults are only resolved in the page tables of the global mapper
+
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult},
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize,
+            PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+use super::BitmapFrameAllocator;
+
+/// Flag of the page table entries of copy-on-write pages
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Turn the writable mapping of `page` into a copy-on-write one, and return its frame so that it
+/// can be shared with `map_cow`.
+///
+/// Read-only pages are returned as they are, since they can be shared without copy-on-write.
+pub fn make_cow(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    page: Page,
+) -> Result<PhysFrame, FlagUpdateError> {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        TranslateResult::Mapped { .. } => return Err(FlagUpdateError::ParentEntryHugePage),
+        _ => return Err(FlagUpdateError::PageNotMapped),
+    };
+    if flags.contains(PageTableFlags::WRITABLE) {
+        let flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+        unsafe { mapper.update_flags(page, flags)?.flush() };
+    }
+    Ok(frame)
+}
+
+/// Map `frame`, which is in use, as a copy-on-write page at `page`, taking a reference to it.
+///
+/// `flags` are the flags the page gets once written to, the page being read-only until then.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that every other writable mapping of
+/// the frame is a copy-on-write one (see `make_cow`), so that the page doesn't see it change.
+pub unsafe fn map_cow(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = if flags.contains(PageTableFlags::WRITABLE) {
+        (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+    } else {
+        flags
+    };
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    frame_allocator.add_reference(frame);
+    Ok(())
+}
+
+/// Try to resolve a page fault at `addr`, which is possible if it is a write to a copy-on-write
+/// page: the page is then remapped writable, to a copy of the frame unless nothing else
+/// references it.
+///
+/// Return whether the fault was resolved, in which case the faulting instruction can be retried.
+pub fn resolve_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present_page =
+        PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if !error_code.contains(write_to_present_page) {
+        return false;
+    }
+
+    // if the fault happened while the paging structures were in use, they can't be used to
+    // resolve it
+    super::try_with_global_paging(|mapper, frame_allocator| {
+        let page = Page::containing_address(addr);
+        let (frame, flags) = match mapper.translate(addr) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+            _ => return false,
+        };
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if frame_allocator.reference_count(frame) == 1 {
+            // the last reference, which can keep the frame
+            return unsafe { mapper.update_flags(page, flags) }
+                .map(|flush| flush.flush())
+                .is_ok();
+        }
+
+        let copy: PhysFrame = match frame_allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        unsafe {
+            let from: *const u8 = frame_ptr(mapper, frame);
+            core::ptr::copy_nonoverlapping(from, frame_ptr(mapper, copy), Size4KiB::SIZE as usize);
+        }
+
+        // the page is mapped, so the page tables for it are there too
+        let (_, flush) = mapper
+            .unmap(page)
+            .expect("failed to unmap a copy-on-write page");
+        flush.flush();
+        unsafe { mapper.map_to(page, copy, flags, frame_allocator) }
+            .expect("failed to remap a copy-on-write page")
+            .flush();
+        // drop the reference of this page
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Return a pointer to the start of `frame`, through the physical memory mapping
+fn frame_ptr(mapper: &OffsetPageTable, frame: PhysFrame) -> *mut u8 {
+    (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
@@ -0,0 +1,288 @@
+//! Inspection of the active page tables, to debug mappings.
+//!
+//! `dump` prints every mapping to serial, coalescing the pages that map contiguous physical memory
+//! with the same flags, and `walk` returns every step of the translation of a single address.
+//!
+//! The tables are the ones of the active address space (in `Cr3`), read through the physical
+//! memory mapping of the given `OffsetPageTable`, so the functions are typically called from
+//! `memory::with_global_paging`.
+//!
+//! Main drawbacks:
+//! - `dump` walks every present entry of every table, which is slow with many 4 KiB pages
+
+use core::fmt;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{OffsetPageTable, PageTable, PageTableFlags, PageTableIndex},
+    PhysAddr, VirtAddr,
+};
+
+use crate::serial_println;
+
+/// The flags shown by `dump` and `walk`; the others (like `ACCESSED`) change on every access and
+/// would prevent coalescing
+const SHOWN_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PageTableFlags::HUGE_PAGE);
+
+/// A range of virtual memory mapped to contiguous physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt_start: VirtAddr,
+    pub phys_start: PhysAddr,
+    pub size: u64,
+    /// The effective flags of the pages, taking the upper levels into account: a page is only
+    /// writable (or user accessible) if every level allows it, and not executable if any level
+    /// forbids it
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// Return the address right after the mapping
+    pub fn virt_end(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.virt_start.as_u64() + self.size)
+    }
+
+    /// Return whether `other` continues this mapping
+    fn is_continued_by(&self, other: &Mapping) -> bool {
+        self.virt_end() == other.virt_start
+            && self.phys_start + self.size == other.phys_start
+            && self.flags == other.flags
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}..{:#018x} -> {:#014x} {:>10} KiB {}",
+            self.virt_start.as_u64(),
+            self.virt_end().as_u64(),
+            self.phys_start.as_u64(),
+            self.size / 1024,
+            FlagsDisplay(self.flags)
+        )
+    }
+}
+
+/// Display of the shown flags as a fixed-width string like `PW-NH`
+struct FlagsDisplay(PageTableFlags);
+
+impl fmt::Display for FlagsDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag, c| if self.0.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}{}{}",
+            flag(PageTableFlags::PRESENT, 'P'),
+            flag(PageTableFlags::WRITABLE, 'W'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'U'),
+            flag(PageTableFlags::NO_EXECUTE, 'N'),
+            flag(PageTableFlags::HUGE_PAGE, 'H'),
+        )
+    }
+}
+
+/// Return the size of the virtual memory covered by an entry of a table of the given level
+fn entry_size(level: u8) -> u64 {
+    1 << (12 + 9 * (u32::from(level) - 1))
+}
+
+/// Return the table at the given physical address, through the physical memory mapping
+fn table_at<'a>(mapper: &'a OffsetPageTable, addr: PhysAddr) -> &'a PageTable {
+    unsafe { &*(mapper.phys_offset() + addr.as_u64()).as_ptr() }
+}
+
+/// Return the shown flags of an entry of a table of the given level.
+///
+/// The `HUGE_PAGE` bit only means that at levels 2 and 3: at level 1 it selects the PAT entry of
+/// the page, and at level 4 it is reserved.
+fn shown_flags(level: u8, entry: PageTableFlags) -> PageTableFlags {
+    let mut flags = entry & SHOWN_FLAGS;
+    if !(2..=3).contains(&level) {
+        flags.remove(PageTableFlags::HUGE_PAGE);
+    }
+    flags
+}
+
+/// Combine the flags of an entry of a table of the given level with the effective flags of the
+/// upper levels
+fn effective_flags(level: u8, upper: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let restrictive = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = shown_flags(level, entry);
+    flags.remove(restrictive - (upper & restrictive));
+    flags | (upper & PageTableFlags::NO_EXECUTE)
+}
+
+/// Call `f` on every mapping of the active page tables, by increasing virtual address (lower half
+/// first), coalescing contiguous pages.
+pub fn for_each_mapping(mapper: &OffsetPageTable, mut f: impl FnMut(&Mapping)) {
+    let mut current: Option<Mapping> = None;
+    let mut visit = |page: Mapping| match current {
+        Some(ref mut mapping) if mapping.is_continued_by(&page) => mapping.size += page.size,
+        _ => {
+            if let Some(mapping) = current.replace(page) {
+                f(&mapping);
+            }
+        }
+    };
+
+    // there are no upper levels to restrict the level 4 entries
+    let all = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let (level_4_frame, _) = Cr3::read();
+    walk_table(
+        mapper,
+        level_4_frame.start_address(),
+        4,
+        VirtAddr::zero(),
+        all,
+        &mut visit,
+    );
+
+    if let Some(mapping) = current {
+        f(&mapping);
+    }
+}
+
+/// Visit the leaf entries of the table at `table_addr`, at the given level, which covers the
+/// virtual memory starting at `base`
+fn walk_table(
+    mapper: &OffsetPageTable,
+    table_addr: PhysAddr,
+    level: u8,
+    base: VirtAddr,
+    upper_flags: PageTableFlags,
+    visit: &mut impl FnMut(Mapping),
+) {
+    let table = table_at(mapper, table_addr);
+    let entry_size = entry_size(level);
+    for (index, entry) in table.iter().enumerate() {
+        let entry_flags = entry.flags();
+        if !entry_flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let virt_start = VirtAddr::new_truncate(base.as_u64() + index as u64 * entry_size);
+        let flags = effective_flags(level, upper_flags, entry_flags);
+        if level == 1 || entry_flags.contains(PageTableFlags::HUGE_PAGE) {
+            visit(Mapping {
+                virt_start,
+                phys_start: entry.addr(),
+                size: entry_size,
+                flags,
+            });
+        } else {
+            walk_table(mapper, entry.addr(), level - 1, virt_start, flags, visit);
+        }
+    }
+}
+
+/// Print every mapping of the active page tables to serial.
+pub fn dump(mapper: &OffsetPageTable) {
+    serial_println!("Page table mappings (Present, Writable, User, No execute, Huge):");
+    for_each_mapping(mapper, |mapping| {
+        serial_println!("    {}", mapping);
+    });
+}
+
+/// One step of the translation of an address: the entry read in the table of one level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    /// Level of the table, from 4 down to 1
+    pub level: u8,
+    /// Physical address of the table
+    pub table: PhysAddr,
+    pub index: PageTableIndex,
+    /// Flags of the entry, as they are in the table
+    pub flags: PageTableFlags,
+    /// Address in the entry: the next table, or the mapped frame for the last step
+    pub addr: PhysAddr,
+}
+
+/// The translation of a virtual address through every level of the page tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Walk {
+    pub addr: VirtAddr,
+    /// The steps taken, ending at the first entry that isn't present or maps a page
+    pub steps: [Option<WalkStep>; 4],
+    /// The physical address `addr` is mapped to, if any
+    pub phys: Option<PhysAddr>,
+    /// The effective flags of the page (see `Mapping::flags`), if mapped
+    pub flags: Option<PageTableFlags>,
+}
+
+impl Walk {
+    /// Return the size of the page mapping the address, if mapped
+    pub fn page_size(&self) -> Option<u64> {
+        self.phys?;
+        let last = self.steps.iter().flatten().last()?;
+        Some(entry_size(last.level))
+    }
+}
+
+impl fmt::Display for Walk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "translation of {:#018x}:", self.addr.as_u64())?;
+        for step in self.steps.iter().flatten() {
+            writeln!(
+                f,
+                "    level {} table {:#014x} [{:3}] -> {:#014x} {}",
+                step.level,
+                step.table.as_u64(),
+                u16::from(step.index),
+                step.addr.as_u64(),
+                FlagsDisplay(shown_flags(step.level, step.flags)),
+            )?;
+        }
+        match (self.phys, self.flags) {
+            (Some(phys), Some(flags)) => {
+                write!(f, "    => {:#014x} {}", phys.as_u64(), FlagsDisplay(flags))
+            }
+            _ => write!(f, "    => not mapped"),
+        }
+    }
+}
+
+/// Translate `addr` through the active page tables, recording every step.
+pub fn walk(mapper: &OffsetPageTable, addr: VirtAddr) -> Walk {
+    let (level_4_frame, _) = Cr3::read();
+    let mut table_addr = level_4_frame.start_address();
+
+    let mut walk = Walk {
+        addr,
+        steps: [None; 4],
+        phys: None,
+        flags: None,
+    };
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for (step, (level, index)) in (1..=4u8).rev().zip(indexes).enumerate() {
+        let entry = &table_at(mapper, table_addr)[index];
+        walk.steps[step] = Some(WalkStep {
+            level,
+            table: table_addr,
+            index,
+            flags: entry.flags(),
+            addr: entry.addr(),
+        });
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            break;
+        }
+        flags = effective_flags(level, flags, entry.flags());
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_offset = addr.as_u64() & (entry_size(level) - 1);
+            walk.phys = Some(entry.addr() + page_offset);
+            walk.flags = Some(flags);
+            break;
+        }
+        table_addr = entry.addr();
+    }
+    walk
+}
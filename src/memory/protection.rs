@@ -0,0 +1,162 @@
+//! Enforcement of W^X on the kernel mappings: no page is both writable and executable.
+//!
+//! The kernel image is remapped from its own ELF program headers, which are loaded along with it
+//! (at `__ehdr_start`, defined by the linker): `.text` is read-only and executable, `.rodata`
+//! read-only and not executable, and the data and bss are writable and not executable. The
+//! segments are page aligned thanks to the `-z separate-loadable-segments` linker argument in the
+//! target specification.
+//!
+//! Everything else the kernel maps (the heap, stacks, ...) is mapped with `NO_EXECUTE`, and so is
+//! the physical memory mapping of the bootloader, at its level 4 entry.
+//!
+//! Main drawbacks:
+//! - the rest of what the bootloader maps (its stack, the boot information) is left as it is
+
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Efer, EferFlags},
+    structures::paging::{
+        page::PageRangeInclusive, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+extern "C" {
+    /// The ELF header of the kernel, defined by the linker
+    static __ehdr_start: ElfHeader;
+}
+
+/// The start of the ELF header of a 64 bits executable
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_headers_offset: u64,
+    section_headers_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+}
+
+/// An ELF program header of a 64 bits executable
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virt_addr: u64,
+    phys_addr: u64,
+    file_size: u64,
+    mem_size: u64,
+    align: u64,
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// A loadable segment of the kernel image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelSegment {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl KernelSegment {
+    /// Return the flags of the pages of the segment
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    /// Return the pages of the segment, or None if it is empty
+    pub fn pages(&self) -> Option<PageRangeInclusive> {
+        if self.size == 0 {
+            return None;
+        }
+        let start = Page::containing_address(self.start);
+        let end = Page::containing_address(self.start + (self.size - 1));
+        Some(Page::range_inclusive(start, end))
+    }
+}
+
+/// Return the loadable segments of the kernel image, as described by its program headers.
+pub fn kernel_segments() -> impl Iterator<Item = KernelSegment> {
+    let header = unsafe { &__ehdr_start };
+    let base = header as *const ElfHeader as u64;
+    let program_headers = unsafe {
+        core::slice::from_raw_parts(
+            (base + header.program_headers_offset) as *const ProgramHeader,
+            usize::from(header.program_header_count),
+        )
+    };
+    program_headers
+        .iter()
+        .filter(|header| header.kind == PT_LOAD)
+        .map(|header| KernelSegment {
+            start: VirtAddr::new(header.virt_addr),
+            size: header.mem_size,
+            writable: header.flags & PF_W != 0,
+            executable: header.flags & PF_X != 0,
+        })
+}
+
+/// Enable the no-execute bit in page tables (EFER.NXE), and the write protection of read-only
+/// pages in kernel mode too (CR0.WP).
+pub fn enable_nx() {
+    unsafe {
+        Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE);
+        Cr0::update(|cr0| *cr0 |= Cr0Flags::WRITE_PROTECT);
+    }
+}
+
+/// Enable NX and remap the kernel image and the physical memory mapping so that no page is both
+/// writable and executable.
+///
+/// Called by `memory::init`.
+pub(super) fn enforce_wx(mapper: &mut OffsetPageTable) {
+    enable_nx();
+
+    for segment in kernel_segments() {
+        // an empty segment has no page, and computing its last address would underflow
+        let pages = match segment.pages() {
+            Some(pages) => pages,
+            None => continue,
+        };
+        assert!(
+            !(segment.writable && segment.executable),
+            "kernel segment at {:?} is both writable and executable",
+            segment.start
+        );
+        assert!(
+            segment.start.is_aligned(Size4KiB::SIZE),
+            "kernel segment at {:?} isn't page aligned",
+            segment.start
+        );
+
+        let flags = segment.page_flags();
+        for page in pages {
+            unsafe { mapper.update_flags(page, flags) }
+                .expect("failed to remap the kernel image")
+                .flush();
+        }
+    }
+
+    // the physical memory mapping has a level 4 entry of its own, and NO_EXECUTE there applies
+    // to everything below it
+    let index = mapper.phys_offset().p4_index();
+    let entry = &mut mapper.level_4_table()[index];
+    entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+    x86_64::instructions::tlb::flush_all();
+}
@@ -0,0 +1,107 @@
+//! Kernel stacks allocated at runtime.
+//!
+//! Every stack lives in its own `Stack` region of the kernel address space, whose first page is
+//! left unmapped: overflowing the stack faults on this guard page instead of silently overwriting
+//! the memory below it.
+//!
+//! Main drawbacks:
+//! - an overflow skipping over the guard page (e.g. with a large stack frame) isn't caught
+//! - stacks are mapped upfront rather than lazily
+
+use x86_64::{
+    structures::paging::{Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::vm::{self, Region, RegionKind};
+
+/// Flags of the pages of the stacks
+const STACK_PAGE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// A kernel stack, with an unmapped guard page below it
+///
+/// Dropping it leaves the stack allocated: it can only be freed with `free_stack`, which is unsafe
+/// since the stack may still be in use.
+#[derive(Debug)]
+pub struct KernelStack {
+    region: Region,
+}
+
+/// Allocate a kernel stack of `pages` mapped pages, with a guard page below them.
+///
+/// Return None if `memory::make_global` wasn't called yet, or if there isn't enough virtual
+/// memory or frames left.
+pub fn allocate_stack(pages: u64) -> Option<KernelStack> {
+    let region = vm::reserve(
+        (pages + 1) * Size4KiB::SIZE,
+        Size4KiB::SIZE,
+        STACK_PAGE_FLAGS,
+        RegionKind::Stack,
+    )?;
+    let stack = KernelStack { region };
+
+    let mapped = super::with_global_paging(|mapper, frame_allocator| {
+        super::map_range(
+            stack.bottom(),
+            stack.size(),
+            STACK_PAGE_FLAGS,
+            mapper,
+            frame_allocator,
+        )
+    });
+    match mapped {
+        Some(Ok(())) => Some(stack),
+        Some(Err(_)) => {
+            // give back the pages mapped until then
+            unsafe { free_stack(stack) };
+            None
+        }
+        None => {
+            vm::release(&stack.region);
+            None
+        }
+    }
+}
+
+/// Unmap a kernel stack and give its memory back.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the stack isn't in use anymore,
+/// including as an interrupt stack in the TSS.
+pub unsafe fn free_stack(stack: KernelStack) {
+    super::with_global_paging(|mapper, frame_allocator| unsafe {
+        stack.region.unmap(mapper, frame_allocator)
+    });
+    vm::release(&stack.region);
+}
+
+impl KernelStack {
+    /// Return the address right after the stack, where its stack pointer starts since it grows
+    /// downwards
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    /// Return the lowest address of the stack, right after its guard page
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.start() + Size4KiB::SIZE
+    }
+
+    /// Return the usable size of the stack, without its guard page
+    pub fn size(&self) -> u64 {
+        self.region.size() - Size4KiB::SIZE
+    }
+
+    /// Return the unmapped page right below the stack
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.region.start())
+    }
+
+    /// Return the region of the stack, including its guard page
+    pub fn region(&self) -> &Region {
+        &self.region
+    }
+}
@@ -0,0 +1,457 @@
+//! Management of the kernel half of the virtual address space.
+//!
+//! Kernel memory (the heap, stacks, MMIO windows...) is placed in regions reserved with `reserve`,
+//! which never overlap each other nor what the bootloader mapped (the kernel image, its stack, the
+//! boot information and the physical memory mapping), recorded by `memory::init`. Regions are
+//! only reserved in the upper half of the address space, which the bootloader leaves unused.
+//!
+//! Reserving a region doesn't map it: that is done with `Region::map` (to newly allocated frames)
+//! or `Region::map_to_phys` (to device memory), and undone with `Region::unmap`. Regions reserved
+//! with `reserve_lazy` are instead mapped one page at a time, by the page fault handler on their
+//! first access (see `resolve_page_fault`).
+//!
+//! Main drawbacks:
+//! - the regions are kept in a fixed-size table (since the heap itself is a region), so at most
+//!   `MAX_REGIONS` of them can exist at once
+//! - finding a free range walks all regions for every candidate address
+
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Start of the kernel half of the address space
+pub const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+/// End of the kernel half of the address space (exclusive), leaving out the last page so that
+/// region ends never overflow
+pub const KERNEL_SPACE_END: u64 = 0xffff_ffff_ffff_f000;
+/// Maximum number of regions, including the ones of the bootloader
+pub const MAX_REGIONS: usize = 64;
+
+/// Size of the virtual memory covered by one level 4 page table entry
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+/// What a region is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// The mapping of the complete physical memory set up by the bootloader
+    PhysicalMemory,
+    /// Anything else mapped by the bootloader: the kernel image, its stack, the boot information
+    Bootloader,
+    Heap,
+    Stack,
+    /// A window on device memory, mapped with `Region::map_to_phys`
+    Mmio,
+    Other,
+}
+
+/// A range of virtual memory reserved for one use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    kind: RegionKind,
+    /// Whether the pages are mapped on demand by `resolve_page_fault`
+    lazy: bool,
+}
+
+static REGIONS: spin::Mutex<[Option<Region>; MAX_REGIONS]> = spin::Mutex::new([None; MAX_REGIONS]);
+
+/// Record the parts of the address space used by the bootloader, from the present entries of the
+/// level 4 page table.
+///
+/// Called by `memory::init`, which forgets any region reserved before.
+pub(super) fn init(level_4_table: &PageTable, physical_memory_offset: VirtAddr) {
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        *regions = [None; MAX_REGIONS];
+
+        let present = level_4_table
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.flags().contains(PageTableFlags::PRESENT));
+        for (slot, (index, entry)) in regions.iter_mut().zip(present) {
+            let kind = if usize::from(physical_memory_offset.p4_index()) == index {
+                RegionKind::PhysicalMemory
+            } else {
+                RegionKind::Bootloader
+            };
+            let start = VirtAddr::new_truncate(index as u64 * LEVEL_4_ENTRY_SIZE);
+            *slot = Some(Region {
+                start,
+                // like every region, the last entry leaves out the last page
+                size: LEVEL_4_ENTRY_SIZE.min(KERNEL_SPACE_END.wrapping_sub(start.as_u64())),
+                flags: entry.flags(),
+                kind,
+                lazy: false,
+            });
+        }
+    })
+}
+
+/// Reserve `size` bytes (rounded up to pages) of the kernel address space, aligned to `align` (a
+/// power of two, at least a page).
+///
+/// The region is placed at the lowest free address and isn't mapped yet. `flags` are the flags
+/// its pages get once mapped. Return None if there is no free range large enough, or no room left
+/// to record the region.
+pub fn reserve(size: u64, align: u64, flags: PageTableFlags, kind: RegionKind) -> Option<Region> {
+    reserve_region(size, align, flags, kind, false)
+}
+
+/// Reserve a lazily-backed region: like `reserve`, but its pages are mapped to zeroed frames by
+/// the page fault handler when they are first accessed.
+pub fn reserve_lazy(
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+    kind: RegionKind,
+) -> Option<Region> {
+    reserve_region(size, align, flags, kind, true)
+}
+
+/// Map the `size` bytes of device memory at `phys` in a new `Mmio` region, with the global mapper,
+/// and return the virtual address of `phys`.
+///
+/// Return None if the region can't be reserved or mapped, or if `memory::make_global` wasn't
+/// called yet.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the physical memory is device
+/// memory (see `Region::map_to_phys`).
+pub unsafe fn map_mmio(phys: PhysAddr, size: u64) -> Option<VirtAddr> {
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::NO_EXECUTE);
+
+    let page_offset = phys.as_u64() % Size4KiB::SIZE;
+    let region = reserve(page_offset + size, 1, FLAGS, RegionKind::Mmio)?;
+    let mapped = super::with_global_paging(|mapper, frame_allocator| {
+        let mapped =
+            unsafe { region.map_to_phys(phys.align_down(Size4KiB::SIZE), mapper, frame_allocator) };
+        if mapped.is_err() {
+            // undo the part mapped before the failure
+            unsafe { region.unmap(mapper, frame_allocator) };
+        }
+        mapped.is_ok()
+    });
+    if mapped != Some(true) {
+        release(&region);
+        return None;
+    }
+    Some(region.start() + page_offset)
+}
+
+/// Unmap the `Mmio` region containing `addr`, as returned by `map_mmio`, and release it.
+///
+/// Return false if `addr` isn't in an `Mmio` region.
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that nothing uses the device memory
+/// through the region anymore.
+pub unsafe fn unmap_mmio(addr: VirtAddr) -> bool {
+    let region = match region_at(addr) {
+        Some(region) if region.kind() == RegionKind::Mmio => region,
+        _ => return false,
+    };
+    super::with_global_paging(|mapper, frame_allocator| unsafe {
+        region.unmap(mapper, frame_allocator)
+    });
+    release(&region)
+}
+
+fn reserve_region(
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+    kind: RegionKind,
+    lazy: bool,
+) -> Option<Region> {
+    assert!(align.is_power_of_two(), "`align` must be a power of two");
+    let align = align.max(Size4KiB::SIZE);
+    let size = x86_64::align_up(size, Size4KiB::SIZE);
+
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let slot = regions.iter().position(Option::is_none)?;
+
+        let mut start = x86_64::align_up(KERNEL_SPACE_START, align);
+        loop {
+            let end = start
+                .checked_add(size)
+                .filter(|&end| end <= KERNEL_SPACE_END)?;
+            // skip past the furthest region overlapping the candidate range
+            let overlap_end = regions
+                .iter()
+                .flatten()
+                .filter(|region| region.start.as_u64() < end && start < region.end().as_u64())
+                .map(|region| region.end().as_u64())
+                .max();
+            match overlap_end {
+                Some(overlap_end) => start = overlap_end.checked_add(align - 1)? & !(align - 1),
+                None => break,
+            }
+        }
+
+        let region = Region {
+            start: VirtAddr::new(start),
+            size,
+            flags,
+            kind,
+            lazy,
+        };
+        regions[slot] = Some(region);
+        Some(region)
+    })
+}
+
+/// Give the range of `region` back, so that it can be reserved again.
+///
+/// The region must have been unmapped first. Return false if it wasn't reserved.
+pub fn release(region: &Region) -> bool {
+    without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        match regions
+            .iter_mut()
+            .find(|slot| slot.as_ref() == Some(region))
+        {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Return the region containing `addr`, if any.
+pub fn region_at(addr: VirtAddr) -> Option<Region> {
+    without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .copied()
+    })
+}
+
+/// Like `region_at`, but return None instead of waiting when the regions are in use, e.g. when
+/// called from a page fault raised while they are.
+fn try_region_at(addr: VirtAddr) -> Option<Region> {
+    without_interrupts(|| {
+        REGIONS
+            .try_lock()?
+            .iter()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .copied()
+    })
+}
+
+/// Call `f` on every region, by increasing address.
+///
+/// `f` must not reserve nor release regions.
+pub fn for_each_region(mut f: impl FnMut(&Region)) {
+    without_interrupts(|| {
+        let regions = REGIONS.lock();
+        let mut last = None;
+        // the table isn't sorted, so look for the next region after the last one every time
+        while let Some(region) = regions
+            .iter()
+            .flatten()
+            .filter(|region| last.map_or(true, |last| region.start > last))
+            .min_by_key(|region| region.start)
+        {
+            f(region);
+            last = Some(region.start);
+        }
+    })
+}
+
+/// Try to resolve a page fault at `addr`, which is possible if it is an access to a page of a
+/// lazily-backed region that isn't mapped yet and that the region's flags allow: the page is then
+/// mapped to a zeroed frame.
+///
+/// Return whether the fault was resolved, in which case the faulting instruction can be retried.
+pub fn resolve_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // if the fault happened while the regions were in use, they can't be looked up
+    let region = match try_region_at(addr) {
+        Some(region) if region.lazy => region,
+        _ => return false,
+    };
+    // the page is mapped already, and the access isn't allowed
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && region.flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        return false;
+    }
+
+    // if the fault happened while the paging structures were in use, they can't be used to
+    // resolve it
+    super::try_with_global_paging(|mapper, frame_allocator| {
+        let frame: PhysFrame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let frame_ptr: *mut u8 =
+            (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { frame_ptr.write_bytes(0, Size4KiB::SIZE as usize) };
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match unsafe { mapper.map_to(page, frame, region.flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    })
+    .unwrap_or(false)
+}
+
+impl Region {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Return the address right after the region
+    ///
+    /// For a region ending the lower half, this is the start of the kernel half.
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.start.as_u64() + self.size)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn flags(&self) -> PageTableFlags {
+        self.flags
+    }
+
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    /// Return whether the region was reserved with `reserve_lazy`
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Map the whole region to newly allocated frames, with huge pages where it is aligned for
+    /// them (see `memory::map_range`).
+    pub fn map<M, A>(
+        &self,
+        mapper: &mut M,
+        frame_allocator: &mut A,
+    ) -> Result<(), MapToError<Size4KiB>>
+    where
+        M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+        A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+    {
+        super::map_range(self.start, self.size, self.flags, mapper, frame_allocator)
+    }
+
+    /// Map the whole region to the physical memory starting at `phys` (page aligned), like device
+    /// memory for an `Mmio` region.
+    ///
+    /// Caching is disabled for the pages of `Mmio` regions.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the physical memory isn't
+    /// used in a way that conflicts with this new mapping, e.g. that it isn't a frame given out by
+    /// the frame allocator.
+    pub unsafe fn map_to_phys<M, A>(
+        &self,
+        phys: PhysAddr,
+        mapper: &mut M,
+        frame_allocator: &mut A,
+    ) -> Result<(), MapToError<Size4KiB>>
+    where
+        M: Mapper<Size4KiB>,
+        A: FrameAllocator<Size4KiB>,
+    {
+        let mut flags = self.flags;
+        if self.kind == RegionKind::Mmio {
+            flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        }
+        for offset in (0..self.size).step_by(Size4KiB::SIZE as usize) {
+            let page = Page::<Size4KiB>::containing_address(self.start + offset);
+            let frame = PhysFrame::containing_address(phys + offset);
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok(())
+    }
+
+    /// Unmap every mapped page of the region, giving their frames back to the frame allocator
+    /// except for `Mmio` regions (whose frames belong to devices).
+    ///
+    /// The page tables themselves are kept, to be reused by later mappings.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that nothing uses the memory of
+    /// the region anymore.
+    pub unsafe fn unmap<M, D>(&self, mapper: &mut M, frame_deallocator: &mut D)
+    where
+        M: Mapper<Size4KiB> + Mapper<Size2MiB> + Translate,
+        D: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>,
+    {
+        let free_frames = self.kind != RegionKind::Mmio;
+        let mut addr = self.start;
+        while addr < self.end() {
+            match mapper.translate(addr) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(_),
+                    ..
+                } => {
+                    let page = Page::<Size2MiB>::containing_address(addr);
+                    let (frame, flush) = Mapper::<Size2MiB>::unmap(mapper, page)
+                        .expect("failed to unmap a huge page");
+                    flush.flush();
+                    if free_frames {
+                        unsafe { frame_deallocator.deallocate_frame(frame) };
+                    }
+                    addr = page.start_address() + Size2MiB::SIZE;
+                }
+                TranslateResult::Mapped { .. } => {
+                    let page = Page::<Size4KiB>::containing_address(addr);
+                    let (frame, flush) =
+                        Mapper::<Size4KiB>::unmap(mapper, page).expect("failed to unmap a page");
+                    flush.flush();
+                    if free_frames {
+                        unsafe { frame_deallocator.deallocate_frame(frame) };
+                    }
+                    addr += Size4KiB::SIZE;
+                }
+                _ => addr += Size4KiB::SIZE,
+            }
+        }
+    }
+}
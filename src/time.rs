@@ -0,0 +1,119 @@
+//! The monotonic clock of the kernel, driven by a timer interrupting `TICK_HZ` times per second.
+//!
+//! `init` programs the PIT to interrupt at `TICK_HZ` on IRQ 0, whose handler counts the ticks (see
+//! `tick`). Once memory is set up, `init_hpet` switches to the HPET if there is one: its timer 0
+//! takes over IRQ 0 at the same rate, and its main counter gives the time with a much finer
+//! resolution than the ticks.
+//!
+//! `uptime` is the time elapsed since `init`, and `sleep` waits for a duration, halting the CPU
+//! until the next interrupt when interrupts are enabled.
+//!
+//! Main drawbacks:
+//! - with the PIT, the resolution of `uptime` is a tick
+//! - with the PIT and interrupts disabled, `sleep` polls the counter of the PIT, which must not be
+//!   interrupted for more than a tick
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
+
+pub mod hpet;
+pub mod pit;
+
+use hpet::{Hpet, HpetError};
+
+/// Frequency of the timer interrupts
+pub const TICK_HZ: u64 = 1000;
+
+/// Number of timer interrupts since `init`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Divisor programmed into the PIT by `init`
+static PIT_DIVISOR: AtomicU64 = AtomicU64::new(0);
+
+/// The HPET once `init_hpet` succeeded, and the uptime when it was enabled
+static HPET: spin::Once<(Hpet, Duration)> = spin::Once::new();
+
+/// What `uptime` is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The ticks of the PIT
+    Pit,
+    /// The main counter of the HPET
+    Hpet,
+}
+
+/// Program the PIT to interrupt at `TICK_HZ`.
+///
+/// Called by `clacos::init`.
+pub fn init() {
+    let divisor = pit::set_periodic(TICK_HZ);
+    PIT_DIVISOR.store(u64::from(divisor), Ordering::Relaxed);
+}
+
+/// Switch to the HPET, if there is one.
+///
+/// On error, the PIT stays in use. Must be called after `memory::make_global`, since the HPET is
+/// found and mapped through the global mapper.
+pub fn init_hpet() -> Result<(), HpetError> {
+    if HPET.r#try().is_some() {
+        return Ok(());
+    }
+    interrupts::without_interrupts(|| {
+        // the HPET counts from 0, so it continues from the current uptime
+        let start = uptime();
+        let hpet = hpet::init(TICK_HZ)?;
+        HPET.call_once(|| (hpet, start));
+        Ok(())
+    })
+}
+
+/// Count a timer interrupt
+///
+/// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Return the number of timer interrupts since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Return what `uptime` is read from.
+pub fn source() -> ClockSource {
+    match HPET.r#try() {
+        Some(_) => ClockSource::Hpet,
+        None => ClockSource::Pit,
+    }
+}
+
+/// Return the time elapsed since `init`, which never goes backwards.
+pub fn uptime() -> Duration {
+    match HPET.r#try() {
+        Some((hpet, start)) => *start + Duration::from_nanos(hpet.nanos()),
+        None => pit::cycles_to_duration(PIT_DIVISOR.load(Ordering::Relaxed) * ticks()),
+    }
+}
+
+/// Wait for `duration` (at least).
+///
+/// The CPU is halted until the next interrupt while waiting when interrupts are enabled. When they
+/// aren't, the clock is polled instead, or the counter of the PIT since the ticks aren't counted.
+pub fn sleep(duration: Duration) {
+    let start = uptime();
+    if interrupts::are_enabled() {
+        while uptime() - start < duration {
+            x86_64::instructions::hlt();
+        }
+    } else if source() == ClockSource::Hpet {
+        while uptime() - start < duration {
+            core::hint::spin_loop();
+        }
+    } else {
+        pit::busy_wait(duration, PIT_DIVISOR.load(Ordering::Relaxed) as u16);
+    }
+}
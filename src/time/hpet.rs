@@ -0,0 +1,134 @@
+//! The High Precision Event Timer, found through its ACPI table.
+//!
+//! Its main counter runs at a fixed frequency (given in femtoseconds per cycle by its capabilities)
+//! from the time it is enabled, and its timer 0 interrupts periodically on IRQ 0 in legacy
+//! replacement mode, in place of the PIT.
+
+use core::ptr;
+
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{acpi::HpetTable, memory::vm};
+
+// Registers of the HPET, as offsets from its base address
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const TIMER_0_CONFIGURATION: usize = 0x100;
+const TIMER_0_COMPARATOR: usize = 0x108;
+
+/// Flag of the capabilities telling that the main counter has 64 bits
+const COUNTER_64_BITS: u64 = 1 << 13;
+/// Flag of the capabilities telling that the legacy replacement mode is supported
+const LEGACY_REPLACEMENT_CAPABLE: u64 = 1 << 15;
+
+/// Flag of the configuration enabling the main counter and the timer interrupts
+const ENABLE: u64 = 1 << 0;
+/// Flag of the configuration routing timer 0 to IRQ 0 and timer 1 to IRQ 8, instead of the PIT
+/// and the RTC
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+// Flags of the configuration of a timer
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Let the next write to the comparator set the period, for periodic timers
+const TIMER_SET_PERIOD: u64 = 1 << 6;
+const TIMER_32_BITS: u64 = 1 << 8;
+
+/// Femtoseconds in a nanosecond
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+/// An error preventing the use of the HPET
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// There is no HPET table, or `memory::make_global` wasn't called yet
+    NoHpet,
+    /// The registers of the HPET couldn't be mapped
+    Mapping,
+    /// The HPET lacks a feature needed here: a 64 bits counter, the legacy replacement mode, or
+    /// a periodic timer 0
+    Unsupported,
+}
+
+/// An enabled HPET
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    registers: VirtAddr,
+    /// Length of a cycle of the main counter, in femtoseconds
+    period_fs: u64,
+}
+
+impl Hpet {
+    fn read(&self, register: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.registers + register).as_ptr()) }
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.registers + register).as_mut_ptr(), value) }
+    }
+
+    /// Return the value of the main counter, in cycles since the HPET was enabled
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// Return the time since the HPET was enabled
+    pub fn nanos(&self) -> u64 {
+        (u128::from(self.counter()) * u128::from(self.period_fs) / FEMTOS_PER_NANO) as u64
+    }
+
+    /// Return the frequency of the main counter
+    pub fn frequency_hz(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+}
+
+/// Find the HPET, reset its main counter and make timer 0 interrupt at `hz` on IRQ 0, in place of
+/// the PIT.
+pub(super) fn init(hz: u64) -> Result<Hpet, HpetError> {
+    let table = HpetTable::find().ok_or(HpetError::NoHpet)?;
+    let phys = table.registers_addr().ok_or(HpetError::NoHpet)?;
+    let registers = unsafe { vm::map_mmio(phys, Size4KiB::SIZE) }.ok_or(HpetError::Mapping)?;
+
+    let mut hpet = Hpet {
+        registers,
+        period_fs: 0,
+    };
+    let capabilities = hpet.read(CAPABILITIES);
+    hpet.period_fs = capabilities >> 32;
+    let timer_0 = hpet.read(TIMER_0_CONFIGURATION);
+    if capabilities & COUNTER_64_BITS == 0
+        || capabilities & LEGACY_REPLACEMENT_CAPABLE == 0
+        || timer_0 & TIMER_PERIODIC_CAPABLE == 0
+        || hpet.period_fs == 0
+    {
+        // the HPET is left untouched, so its registers won't be used
+        unsafe { vm::unmap_mmio(registers) };
+        return Err(HpetError::Unsupported);
+    }
+
+    // stop the counter while it is set up
+    let configuration = hpet.read(CONFIGURATION);
+    hpet.write(
+        CONFIGURATION,
+        configuration & !(ENABLE | LEGACY_REPLACEMENT),
+    );
+    hpet.write(MAIN_COUNTER, 0);
+
+    let period = (hpet.frequency_hz() + hz / 2) / hz;
+    hpet.write(
+        TIMER_0_CONFIGURATION,
+        (timer_0 & !TIMER_32_BITS) | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_SET_PERIOD,
+    );
+    // the first write sets the comparator (the first interrupt, the counter being 0), the second
+    // one the period
+    hpet.write(TIMER_0_COMPARATOR, period);
+    hpet.write(TIMER_0_COMPARATOR, period);
+
+    hpet.write(CONFIGURATION, configuration | ENABLE | LEGACY_REPLACEMENT);
+    Ok(hpet)
+}
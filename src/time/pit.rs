@@ -0,0 +1,73 @@
+//! The Programmable Interval Timer (8253/8254), whose channel 0 is wired to IRQ 0.
+//!
+//! Channel 0 is used as a rate generator: its counter counts down from a divisor of the input
+//! clock, at `FREQUENCY_HZ`, raising IRQ 0 and reloading every time it reaches 0.
+
+use core::time::Duration;
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the input clock of the PIT
+pub const FREQUENCY_HZ: u64 = 1_193_182;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+
+/// Command selecting channel 0 (bits 6-7), with the divisor written low byte then high byte (bits
+/// 4-5), in rate generator mode (bits 1-3), counting in binary (bit 0)
+const SET_CHANNEL_0_RATE: u8 = 0b0011_0100;
+/// Command latching the counter of channel 0, so that both its bytes are read at once
+const LATCH_CHANNEL_0: u8 = 0;
+
+/// Program channel 0 to interrupt at `hz` (as close as the divisor allows), and return the divisor.
+pub fn set_periodic(hz: u64) -> u16 {
+    let divisor = ((FREQUENCY_HZ + hz / 2) / hz).clamp(1, u64::from(u16::MAX)) as u16;
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+    unsafe {
+        command.write(SET_CHANNEL_0_RATE);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+    divisor
+}
+
+/// Return the current value of the counter of channel 0, which counts down from the divisor.
+pub fn count() -> u16 {
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+    unsafe {
+        command.write(LATCH_CHANNEL_0);
+        let low = channel_0.read();
+        let high = channel_0.read();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/// Return the duration of `cycles` cycles of the input clock
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    Duration::from_nanos((u128::from(cycles) * 1_000_000_000 / u128::from(FREQUENCY_HZ)) as u64)
+}
+
+/// Spin until `duration` has elapsed, by polling the counter of channel 0 programmed with
+/// `divisor`.
+///
+/// The counter must be polled at least once per period for the time to be right, which is the
+/// case unless the loop is interrupted.
+pub fn busy_wait(duration: Duration, divisor: u16) {
+    let cycles = duration.as_nanos() * u128::from(FREQUENCY_HZ) / 1_000_000_000;
+    let mut elapsed: u128 = 0;
+    let mut last = count();
+    while elapsed < cycles {
+        core::hint::spin_loop();
+        let now = count();
+        // the counter goes from the divisor down to 1, then starts over
+        let delta = if now <= last {
+            u32::from(last - now)
+        } else {
+            u32::from(last) + u32::from(divisor) - u32::from(now)
+        };
+        elapsed += u128::from(delta);
+        last = now;
+    }
+}
@@ -0,0 +1,150 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    memory::{
+        self,
+        address_space::{self, AddressSpace},
+        BitmapFrameAllocator,
+    },
+};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Page, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// A page in the user part of the address spaces
+const USER_ADDR: u64 = 0x0000_7000_0000_0000;
+
+const USER_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+static STATIC_VALUE: u64 = 0xdead_beef;
+
+fn user_page() -> Page {
+    Page::containing_address(VirtAddr::new(USER_ADDR))
+}
+
+fn used_frames() -> usize {
+    memory::with_global_paging(|_, frame_allocator| frame_allocator.used_frames()).unwrap()
+}
+
+/// Run `f` with `space` as the active address space
+fn run_in<R>(space: &AddressSpace, f: impl FnOnce() -> R) -> R {
+    unsafe { space.switch_to() };
+    let result = f();
+    unsafe { AddressSpace::switch_to_kernel() };
+    result
+}
+
+#[test_case]
+fn user_page_is_not_kernel() {
+    assert!(address_space::is_user_page(user_page()));
+    let kernel_page = Page::containing_address(VirtAddr::from_ptr(&STATIC_VALUE));
+    assert!(!address_space::is_user_page(kernel_page));
+    let heap_page = Page::containing_address(VirtAddr::new(allocator::heap_start() as u64));
+    assert!(!address_space::is_user_page(heap_page));
+}
+
+#[test_case]
+fn kernel_is_mapped_in_new_space() {
+    let kernel_frame = Cr3::read().0;
+    let space = AddressSpace::new().expect("address space creation failed");
+    assert_ne!(space.level_4_frame(), kernel_frame);
+
+    let boxed = Box::new(42u64);
+    let (value, heap_value, active) = run_in(&space, || {
+        // the heap can grow from the new address space, the mapping being shared
+        let vec: Vec<u64> = (0..1000).collect();
+        let sum: u64 = vec.iter().sum();
+        assert_eq!(sum, 999 * 1000 / 2);
+        (
+            unsafe { core::ptr::read_volatile(&STATIC_VALUE) },
+            *boxed,
+            space.is_active(),
+        )
+    });
+    assert_eq!(value, 0xdead_beef);
+    assert_eq!(heap_value, 42);
+    assert!(active);
+    assert!(!space.is_active());
+    assert_eq!(Cr3::read().0, kernel_frame);
+}
+
+#[test_case]
+fn address_spaces_are_isolated() {
+    let mut first = AddressSpace::new().expect("address space creation failed");
+    let mut second = AddressSpace::new().expect("address space creation failed");
+    first.map_user(user_page(), USER_FLAGS).unwrap();
+    second.map_user(user_page(), USER_FLAGS).unwrap();
+
+    // the same address is mapped to different frames
+    let first_phys = first.mapper().translate_addr(VirtAddr::new(USER_ADDR));
+    let second_phys = second.mapper().translate_addr(VirtAddr::new(USER_ADDR));
+    assert!(first_phys.is_some());
+    assert!(second_phys.is_some());
+    assert_ne!(first_phys, second_phys);
+
+    // and not mapped at all in the kernel address space
+    let kernel_phys =
+        memory::with_global_paging(|mapper, _| mapper.translate_addr(VirtAddr::new(USER_ADDR)))
+            .unwrap();
+    assert_eq!(kernel_phys, None);
+
+    let ptr: *mut u64 = VirtAddr::new(USER_ADDR).as_mut_ptr();
+    run_in(&first, || unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(1);
+    });
+    run_in(&second, || unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(2);
+    });
+    assert_eq!(run_in(&first, || unsafe { ptr.read_volatile() }), 1);
+    assert_eq!(run_in(&second, || unsafe { ptr.read_volatile() }), 2);
+}
+
+#[test_case]
+fn drop_frees_page_tables() {
+    let used = used_frames();
+    let mut space = AddressSpace::new().expect("address space creation failed");
+    space.map_user(user_page(), USER_FLAGS).unwrap();
+    space.map_user(user_page() + 1, USER_FLAGS).unwrap();
+    // the level 4 table, the 3 tables below it and the 2 pages
+    assert_eq!(used_frames(), used + 6);
+
+    drop(space);
+    assert_eq!(used_frames(), used);
+}
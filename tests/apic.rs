@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{arch::x86_64::__cpuid, panic::PanicInfo};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    acpi::{Madt, MadtEntry},
+    allocator, apic,
+    interrupts::{InterruptIndex, PICS},
+    memory::{self, vm, BitmapFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+#[test_case]
+fn madt_lists_the_current_cpu() {
+    let madt = Madt::find().expect("no MADT");
+    let id = apic::local_apic_id().unwrap();
+    assert!(madt
+        .entries()
+        .any(|entry| matches!(entry, MadtEntry::LocalApic { apic_id, .. } if apic_id == id)));
+    assert!(madt
+        .entries()
+        .any(|entry| matches!(entry, MadtEntry::IoApic { .. })));
+}
+
+#[test_case]
+fn local_apic_is_enabled() {
+    assert!(apic::is_enabled());
+    // the initial APIC ID of the CPU is in bits 24..32 of EBX
+    let initial_id = (unsafe { __cpuid(1) }.ebx >> 24) as u8;
+    assert_eq!(apic::local_apic_id(), Some(initial_id));
+
+    let local_apic = Madt::find().unwrap().local_apic_addr();
+    assert!(!local_apic.is_null());
+}
+
+#[test_case]
+fn pics_are_masked() {
+    let masks = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        PICS.lock().read_masks()
+    });
+    assert_eq!(masks, [0xff, 0xff]);
+}
+
+#[test_case]
+fn registers_are_in_mmio_regions() {
+    let mut mmio_regions = 0;
+    vm::for_each_region(|region| {
+        if region.kind() == vm::RegionKind::Mmio {
+            mmio_regions += 1;
+        }
+    });
+    // the local APIC and at least an I/O APIC
+    assert!(mmio_regions >= 2);
+}
+
+#[test_case]
+fn legacy_irqs_are_routed() {
+    let id = apic::local_apic_id().unwrap();
+    for index in [InterruptIndex::Timer, InterruptIndex::Keyboard] {
+        let redirection = apic::redirection(index.irq()).expect("IRQ not handled");
+        assert_eq!(redirection.vector, index.as_u8());
+        assert_eq!(redirection.destination, id);
+        assert!(!redirection.masked);
+    }
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    // `hlt` only returns on an interrupt, and the timer is the only source here, so this would
+    // hang if it didn't go through the I/O APIC or wasn't acknowledged
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,130 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use clacos::allocator::{buddy::BuddyAllocator, Locked};
+
+extern crate alloc;
+
+/// Size of the memory area handed to the allocator under test
+const ARENA_SIZE: usize = 64 * 1024; // 64 KiB
+
+/// Size of the largest block the allocator can make from the arena (the end of the arena holds
+/// the allocator's metadata, so the arena can not be a single block)
+const LARGEST_BLOCK: usize = ARENA_SIZE / 2;
+
+/// Backing memory for the allocator under test, page aligned like a real heap would be
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+/// The allocator under test (not the global one, which this test doesn't use)
+static ALLOCATOR: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        let arena_start = core::ptr::addr_of_mut!(ARENA) as usize;
+        ALLOCATOR.lock().init(arena_start, ARENA_SIZE);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Allocate from the allocator under test, panicking when out of memory
+fn alloc(layout: Layout) -> *mut u8 {
+    let ptr = unsafe { ALLOCATOR.alloc(layout) };
+    assert!(!ptr.is_null(), "allocation of {:?} failed", layout);
+    ptr
+}
+
+fn dealloc(ptr: *mut u8, layout: Layout) {
+    unsafe { ALLOCATOR.dealloc(ptr, layout) }
+}
+
+/// Check that basic allocations works without error
+#[test_case]
+fn simple_allocation() {
+    let layout = Layout::new::<u64>();
+    let value_1 = alloc(layout) as *mut u64;
+    let value_2 = alloc(layout) as *mut u64;
+    unsafe {
+        value_1.write(41);
+        value_2.write(42);
+        assert_eq!(value_1.read(), 41);
+        assert_eq!(value_2.read(), 42);
+    }
+    dealloc(value_1 as *mut u8, layout);
+    dealloc(value_2 as *mut u8, layout);
+}
+
+/// Check that blocks are aligned on their size
+#[test_case]
+fn blocks_are_aligned() {
+    let mut size = 1;
+    while size <= LARGEST_BLOCK {
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        let ptr = alloc(layout);
+        assert_eq!(ptr as usize % size, 0);
+        dealloc(ptr, layout);
+        size *= 2;
+    }
+}
+
+/// Check that the two halves of a split block are handed out one after the other
+#[test_case]
+fn buddies_are_adjacent() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let first = alloc(layout) as usize;
+    let second = alloc(layout) as usize;
+    assert_eq!(first ^ 64, second);
+    dealloc(first as *mut u8, layout);
+    dealloc(second as *mut u8, layout);
+}
+
+/// Check that memory is correctly reclaimed when dropping values
+#[test_case]
+fn many_allocations() {
+    let layout = Layout::new::<usize>();
+    for i in 0..ARENA_SIZE {
+        let ptr = alloc(layout) as *mut usize;
+        unsafe {
+            ptr.write(i);
+            assert_eq!(ptr.read(), i);
+        }
+        dealloc(ptr as *mut u8, layout);
+    }
+}
+
+/// Check that freeing every small block coalesces them back into the largest block
+#[test_case]
+fn small_blocks_coalesce() {
+    // split the largest block all the way down, into 64-byte blocks
+    const COUNT: usize = LARGEST_BLOCK / 64;
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let mut blocks = [core::ptr::null_mut(); COUNT];
+    for block in blocks.iter_mut() {
+        *block = alloc(small);
+    }
+
+    // free them in an order that makes buddies rarely free at the same time
+    for block in blocks.iter().step_by(2).chain(blocks.iter().skip(1).step_by(2)) {
+        dealloc(*block, small);
+    }
+
+    let large = Layout::from_size_align(LARGEST_BLOCK, 8).unwrap();
+    let ptr = alloc(large);
+    dealloc(ptr, large);
+}
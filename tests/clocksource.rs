@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{panic::PanicInfo, time::Duration};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    clocksource::{self, ClocksourceError, Source},
+    memory::{self, BitmapFrameAllocator},
+    serial_print,
+    time::{self, TICK_HZ},
+};
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Assert that `a` and `b` differ by less than `percent`%
+fn assert_close(a: u64, b: u64, percent: u64) {
+    assert!(
+        a.abs_diff(b) * 100 < a.max(b) * percent,
+        "{} and {} differ by more than {}%",
+        a,
+        b,
+        percent
+    );
+}
+
+// The tests are run in order: the calibration is checked against the PIT, then against the HPET,
+// before the TSC is taken into use.
+
+static mut PIT_FREQUENCY: u64 = 0;
+
+#[test_case]
+fn calibrate_against_pit() {
+    let frequency = clocksource::calibrate().unwrap();
+    assert!(frequency > 0);
+    // with interrupts disabled, the counter of the PIT is polled instead of the ticks
+    let polled = without_interrupts(clocksource::calibrate).unwrap();
+    assert_close(frequency, polled, 5);
+    unsafe { PIT_FREQUENCY = frequency };
+}
+
+#[test_case]
+fn calibrate_against_hpet() {
+    time::init_hpet().expect("HPET initialization failed");
+    let frequency = clocksource::calibrate().unwrap();
+    assert_close(frequency, unsafe { PIT_FREQUENCY }, 5);
+}
+
+#[test_case]
+fn init_uses_tsc_only_if_invariant() {
+    let before = clocksource::now();
+    match clocksource::init() {
+        Ok(()) => {
+            assert!(clocksource::is_invariant());
+            assert_eq!(clocksource::source(), Source::Tsc);
+            assert!(clocksource::frequency_hz().unwrap() > 0);
+        }
+        Err(error) => {
+            assert_eq!(error, ClocksourceError::NotInvariant);
+            assert!(!clocksource::is_invariant());
+            assert_eq!(clocksource::source(), Source::Uptime);
+            assert_eq!(clocksource::frequency_hz(), None);
+        }
+    }
+    assert!(clocksource::now() >= before);
+}
+
+#[test_case]
+fn now_is_monotonic() {
+    let mut last = clocksource::now();
+    for _ in 0..10_000 {
+        let now = clocksource::now();
+        assert!(now >= last, "now went from {} back to {}", last, now);
+        last = now;
+    }
+}
+
+/// Check that the TSC and the timer interrupts agree over a long enough interval for emulation
+/// delays not to matter
+#[test_case]
+fn now_agrees_with_timer_interrupts() {
+    // `now` is `time::uptime` without the TSC, which there is no point in comparing with itself
+    if clocksource::source() == Source::Uptime {
+        serial_print!("(skipped: no invariant TSC) ");
+        return;
+    }
+
+    let start = clocksource::now();
+    let start_ticks = time::ticks();
+    time::sleep(Duration::from_secs(1));
+    let elapsed = clocksource::now() - start;
+    let ticks = time::ticks() - start_ticks;
+
+    let expected_ticks = elapsed * TICK_HZ / 1_000_000_000;
+    assert_close(ticks, expected_ticks, 25);
+}
@@ -0,0 +1,189 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    memory::{
+        self, cow,
+        vm::{self, Region, RegionKind},
+        BitmapFrameAllocator,
+    },
+};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+        Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Number of words in a page
+const WORDS: usize = Size4KiB::SIZE as usize / 8;
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Return the physical address `addr` is mapped to
+fn translate(addr: VirtAddr) -> PhysAddr {
+    memory::with_global_paging(|mapper, _| mapper.translate_addr(addr))
+        .unwrap()
+        .expect("address not mapped")
+}
+
+fn reference_count(frame: PhysAddr) -> usize {
+    let frame = PhysFrame::containing_address(frame);
+    memory::with_global_paging(|_, frame_allocator| frame_allocator.reference_count(frame)).unwrap()
+}
+
+/// Reserve two pages, map the first one with `value + i` in each word `i` and share it with the
+/// second one
+fn shared_pages(value: u64) -> (Region, *mut u64, *mut u64) {
+    let region = vm::reserve(2 * Size4KiB::SIZE, 1, FLAGS, RegionKind::Other).unwrap();
+    let first = region.start();
+    let second = first + Size4KiB::SIZE;
+    memory::with_global_paging(|mapper, frame_allocator| {
+        memory::map_range(first, Size4KiB::SIZE, FLAGS, mapper, frame_allocator)
+    })
+    .unwrap()
+    .expect("mapping failed");
+    let first_ptr: *mut u64 = first.as_mut_ptr();
+    for i in 0..WORDS {
+        unsafe { first_ptr.add(i).write_volatile(value + i as u64) };
+    }
+
+    memory::with_global_paging(|mapper, frame_allocator| {
+        let frame = cow::make_cow(mapper, Page::containing_address(first)).unwrap();
+        unsafe {
+            cow::map_cow(
+                mapper,
+                Page::containing_address(second),
+                frame,
+                FLAGS,
+                frame_allocator,
+            )
+        }
+        .expect("mapping failed");
+    })
+    .unwrap();
+    (region, first_ptr, second.as_mut_ptr())
+}
+
+/// Unmap `region` and release it
+fn unmap_and_release(region: &Region) {
+    memory::with_global_paging(|mapper, frame_allocator| unsafe {
+        region.unmap(mapper, frame_allocator)
+    })
+    .unwrap();
+    assert!(vm::release(region));
+}
+
+/// Check that a frame is only freed once every reference to it is dropped
+#[test_case]
+fn reference_counts() {
+    memory::with_global_paging(|_, frame_allocator| {
+        let used = frame_allocator.used_frames();
+        let frame: PhysFrame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame_allocator.reference_count(frame), 1);
+
+        frame_allocator.add_reference(frame);
+        frame_allocator.add_reference(frame);
+        assert_eq!(frame_allocator.reference_count(frame), 3);
+        assert_eq!(frame_allocator.used_frames(), used + 1);
+
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.reference_count(frame), 1);
+        assert_eq!(frame_allocator.used_frames(), used + 1);
+
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.reference_count(frame), 0);
+        assert_eq!(frame_allocator.used_frames(), used);
+    })
+    .unwrap();
+}
+
+/// Check that shared pages map the same frame, read-only, until written to
+#[test_case]
+fn reads_share_the_frame() {
+    let (region, first, second) = shared_pages(42);
+    assert_eq!(unsafe { second.read_volatile() }, 42);
+    let frame = translate(VirtAddr::from_ptr(first));
+    assert_eq!(translate(VirtAddr::from_ptr(second)), frame);
+    assert_eq!(reference_count(frame), 2);
+
+    let flags = memory::with_global_paging(|mapper, _| {
+        memory::dump::walk(mapper, VirtAddr::from_ptr(second)).flags
+    })
+    .unwrap()
+    .unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+    unmap_and_release(&region);
+    assert_eq!(reference_count(frame), 0);
+}
+
+/// Check that writing to a shared page gives it a copy of the frame, leaving the other page as it
+/// was
+#[test_case]
+fn write_copies_the_frame() {
+    let (region, first, second) = shared_pages(42);
+    let frame = translate(VirtAddr::from_ptr(first));
+
+    unsafe { second.write_volatile(43) };
+    assert_eq!(unsafe { second.read_volatile() }, 43);
+    assert_eq!(unsafe { first.read_volatile() }, 42);
+    assert_ne!(translate(VirtAddr::from_ptr(second)), frame);
+    assert_eq!(translate(VirtAddr::from_ptr(first)), frame);
+    assert_eq!(reference_count(frame), 1);
+
+    // the first page is the last reference to its frame, so it keeps it
+    unsafe { first.write_volatile(44) };
+    assert_eq!(unsafe { first.read_volatile() }, 44);
+    assert_eq!(translate(VirtAddr::from_ptr(first)), frame);
+    assert_eq!(unsafe { second.read_volatile() }, 43);
+
+    unmap_and_release(&region);
+    assert_eq!(reference_count(frame), 0);
+}
+
+/// Check that the whole page is copied
+#[test_case]
+fn copy_keeps_the_page_content() {
+    let (region, first, second) = shared_pages(1000);
+
+    unsafe { second.write_volatile(0) };
+    for i in 1..WORDS {
+        assert_eq!(unsafe { second.add(i).read_volatile() }, 1000 + i as u64);
+    }
+    for i in 0..WORDS {
+        assert_eq!(unsafe { first.add(i).read_volatile() }, 1000 + i as u64);
+    }
+    unmap_and_release(&region);
+}
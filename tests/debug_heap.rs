@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use clacos::allocator::{debug::DebugAllocator, linked_list::LinkedListAllocator, Locked};
+
+extern crate alloc;
+
+/// Size of the memory area handed to the allocator under test
+const ARENA_SIZE: usize = 64 * 1024; // 64 KiB
+
+/// Backing memory for the allocator under test, page aligned like a real heap would be
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+/// The allocator under test (not the global one, which this test doesn't use)
+static ALLOCATOR: DebugAllocator<Locked<LinkedListAllocator>> =
+    DebugAllocator::new(Locked::new(LinkedListAllocator::new()));
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        let arena_start = core::ptr::addr_of_mut!(ARENA) as usize;
+        ALLOCATOR.inner().lock().init(arena_start, ARENA_SIZE);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Allocate from the allocator under test, panicking when out of memory
+fn alloc(layout: Layout) -> *mut u8 {
+    let ptr = unsafe { ALLOCATOR.alloc(layout) };
+    assert!(!ptr.is_null(), "allocation of {:?} failed", layout);
+    ptr
+}
+
+fn dealloc(ptr: *mut u8, layout: Layout) {
+    unsafe { ALLOCATOR.dealloc(ptr, layout) }
+}
+
+/// Check that the whole allocated memory can be used without tripping the red zones, whatever the
+/// alignment
+#[test_case]
+fn allocated_memory_is_usable() {
+    let mut align = 1;
+    while align <= 4096 {
+        let layout = Layout::from_size_align(100, align).unwrap();
+        let ptr = alloc(layout);
+        assert_eq!(ptr as usize % align, 0);
+        unsafe { ptr.write_bytes(0, layout.size()) };
+        dealloc(ptr, layout);
+        align *= 2;
+    }
+}
+
+/// Check that freed memory is poisoned
+#[test_case]
+fn freed_memory_is_poisoned() {
+    let layout = Layout::new::<[u8; 32]>();
+    let ptr = alloc(layout);
+    unsafe { ptr.write_bytes(0, layout.size()) };
+    dealloc(ptr, layout);
+
+    // the memory is still in quarantine, so reading it is fine
+    let data = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+    assert!(data.iter().all(|&b| b == 0x6b));
+}
+
+/// Check that memory goes back to the wrapped allocator after leaving quarantine
+#[test_case]
+fn memory_is_reclaimed() {
+    let layout = Layout::from_size_align(ARENA_SIZE / 8, 8).unwrap();
+    for _ in 0..ARENA_SIZE {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use clacos::{
+    allocator::{debug::DebugAllocator, linked_list::LinkedListAllocator, Locked},
+    exit_qemu, serial_print, serial_println, QemuExitCode,
+};
+
+extern crate alloc;
+
+const ARENA_SIZE: usize = 4096;
+
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+static ALLOCATOR: DebugAllocator<Locked<LinkedListAllocator>> =
+    DebugAllocator::new(Locked::new(LinkedListAllocator::new()));
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+/// Check that writing one byte past the end of an allocation is caught
+fn should_fail() {
+    serial_print!("debug_heap_red_zone_overwrite::should_fail...\t");
+    let layout = Layout::new::<[u8; 10]>();
+    let ptr = unsafe { ALLOCATOR.alloc(layout) };
+    unsafe {
+        ptr.add(layout.size()).write(0);
+        ALLOCATOR.dealloc(ptr, layout);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        let arena_start = core::ptr::addr_of_mut!(ARENA) as usize;
+        ALLOCATOR.inner().lock().init(arena_start, ARENA_SIZE);
+    }
+
+    should_fail();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    memory::{
+        self,
+        vm::{self, Region, RegionKind},
+        BitmapFrameAllocator,
+    },
+};
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB, Translate},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+const PAGES: u64 = 16;
+
+/// Return the number of frames in use
+fn used_frames() -> usize {
+    memory::with_global_paging(|_, frame_allocator| frame_allocator.used_frames()).unwrap()
+}
+
+/// Check whether `addr` is mapped
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_global_paging(|mapper, _| mapper.translate_addr(addr).is_some()).unwrap()
+}
+
+/// Return the number of mapped pages of `region`
+fn mapped_pages(region: &Region) -> usize {
+    (0..region.size())
+        .step_by(Size4KiB::SIZE as usize)
+        .filter(|&offset| is_mapped(region.start() + offset))
+        .count()
+}
+
+/// Unmap `region` and release it
+fn unmap_and_release(region: &Region) {
+    memory::with_global_paging(|mapper, frame_allocator| unsafe {
+        region.unmap(mapper, frame_allocator)
+    })
+    .unwrap();
+    assert!(vm::release(region));
+}
+
+/// Check that a lazy region isn't mapped until it is accessed
+#[test_case]
+fn lazy_region_starts_unmapped() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = vm::reserve_lazy(PAGES * Size4KiB::SIZE, 1, flags, RegionKind::Other).unwrap();
+    assert!(region.is_lazy());
+    assert_eq!(mapped_pages(&region), 0);
+    assert!(vm::release(&region));
+}
+
+/// Check that writing to a lazy region maps the accessed page only, to a zeroed frame
+#[test_case]
+fn write_maps_one_page() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = vm::reserve_lazy(PAGES * Size4KiB::SIZE, 1, flags, RegionKind::Other).unwrap();
+    let used = used_frames();
+
+    let page = region.start() + 3 * Size4KiB::SIZE;
+    let ptr: *mut u64 = (page + 8u64).as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(is_mapped(page));
+    assert_eq!(mapped_pages(&region), 1);
+    // one frame for the page, plus maybe some for page tables
+    assert!(used_frames() > used);
+
+    // the rest of the page is zeroed
+    let words: *const u64 = page.as_ptr();
+    for i in (0..Size4KiB::SIZE as usize / 8).filter(|&i| i != 1) {
+        assert_eq!(unsafe { words.add(i).read_volatile() }, 0);
+    }
+
+    unmap_and_release(&region);
+}
+
+/// Check that reading from a lazy region maps a zeroed page too, even in a read-only region
+#[test_case]
+fn read_maps_zeroed_page() {
+    let region = vm::reserve_lazy(
+        PAGES * Size4KiB::SIZE,
+        1,
+        PageTableFlags::PRESENT,
+        RegionKind::Other,
+    )
+    .unwrap();
+
+    let ptr: *const u64 = (region.end() - 8u64).as_ptr();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    assert_eq!(mapped_pages(&region), 1);
+
+    unmap_and_release(&region);
+}
+
+/// Check that every page of a lazy region can be touched, and that unmapping gives all the frames
+/// back
+#[test_case]
+fn touch_every_page() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = vm::reserve_lazy(PAGES * Size4KiB::SIZE, 1, flags, RegionKind::Other).unwrap();
+
+    for offset in (0..region.size()).step_by(Size4KiB::SIZE as usize) {
+        let ptr: *mut u64 = (region.start() + offset).as_mut_ptr();
+        unsafe { ptr.write_volatile(offset) };
+    }
+    assert_eq!(mapped_pages(&region), PAGES as usize);
+    for offset in (0..region.size()).step_by(Size4KiB::SIZE as usize) {
+        let ptr: *const u64 = (region.start() + offset).as_ptr();
+        assert_eq!(unsafe { ptr.read_volatile() }, offset);
+    }
+
+    let used = used_frames();
+    unmap_and_release(&region);
+    assert_eq!(mapped_pages(&region), 0);
+    assert_eq!(used_frames(), used - PAGES as usize);
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use clacos::{exit_qemu, interrupts::exceptions, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::ExceptionVector;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("exception_divide_error::divide_by_zero...\t");
+
+    clacos::init();
+    // a division by zero in Rust panics before dividing, so divide with the instruction itself
+    unsafe {
+        core::arch::asm!(
+            "xor edx, edx",
+            "mov eax, 1",
+            "xor ecx, ecx",
+            "div ecx",
+            out("eax") _,
+            out("ecx") _,
+            out("edx") _,
+        )
+    };
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    match exceptions::last_exception() {
+        Some(exception) if exception.vector == ExceptionVector::Division => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        exception => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: unexpected exception {:?}\n", exception);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    loop {}
+}
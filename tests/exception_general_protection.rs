@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use clacos::{exit_qemu, interrupts::exceptions, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{DescriptorTable, ExceptionVector};
+
+/// A selector of the GDT way past its end
+const INVALID_SELECTOR: u16 = 0x1230;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("exception_general_protection::load_invalid_selector...\t");
+
+    clacos::init();
+    unsafe {
+        core::arch::asm!("mov ds, {0:x}", in(reg) INVALID_SELECTOR, options(nostack));
+    }
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    let exception = exceptions::last_exception();
+    // the error code is the selector, decoded as an index in the GDT
+    let decoded = exception
+        .and_then(|exception| exception.selector())
+        .map(|selector| {
+            (
+                selector.index(),
+                selector.descriptor_table(),
+                selector.external(),
+            )
+        });
+    match exception {
+        Some(exception)
+            if exception.vector == ExceptionVector::GeneralProtection
+                && exception.error_code == Some(u64::from(INVALID_SELECTOR))
+                && decoded
+                    == Some((
+                        u64::from(INVALID_SELECTOR >> 3),
+                        DescriptorTable::Gdt,
+                        false,
+                    )) =>
+        {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        exception => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: unexpected exception {:?}\n", exception);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    loop {}
+}
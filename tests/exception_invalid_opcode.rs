@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use clacos::{
+    exit_qemu,
+    interrupts::exceptions::{self, Details},
+    serial_print, serial_println, QemuExitCode,
+};
+use x86_64::structures::idt::ExceptionVector;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("exception_invalid_opcode::invalid_opcode...\t");
+
+    clacos::init();
+    unsafe { core::arch::asm!("ud2") };
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    match exceptions::last_exception() {
+        Some(exception)
+            if exception.vector == ExceptionVector::InvalidOpcode
+                && exception.error_code.is_none()
+                && exception.details == Details::None =>
+        {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        exception => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: unexpected exception {:?}\n", exception);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    loop {}
+}
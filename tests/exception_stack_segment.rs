@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use clacos::{exit_qemu, interrupts::exceptions, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::ExceptionVector;
+
+/// An address that isn't canonical
+const NON_CANONICAL: u64 = 0x8000_0000_0000_0000;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("exception_stack_segment::non_canonical_stack_access...\t");
+
+    clacos::init();
+    // accesses based on `rbp` go through the stack segment, so a non-canonical address raises a
+    // #SS instead of a #GP, while `rsp` stays valid for the handler
+    unsafe {
+        core::arch::asm!(
+            "mov rbp, {addr}",
+            "mov rax, [rbp]",
+            "ud2",
+            addr = in(reg) NON_CANONICAL,
+            options(noreturn),
+        )
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    match exceptions::last_exception() {
+        // not related to a segment selector
+        Some(exception)
+            if exception.vector == ExceptionVector::Stack
+                && exception.error_code == Some(0)
+                && exception.selector().is_none() =>
+        {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        exception => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: unexpected exception {:?}\n", exception);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    loop {}
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use core::{arch::asm, panic::PanicInfo};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    interrupts::exceptions::{self, Details, Exception},
+    memory::{self, BitmapFrameAllocator},
+};
+use x86_64::{
+    structures::idt::{DescriptorTable, ExceptionVector, InterruptStackFrameValue},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Return an exception raised at address 0, as the handlers would record it
+fn fake_exception(vector: ExceptionVector, error_code: Option<u64>) -> Exception {
+    Exception {
+        vector,
+        error_code,
+        details: Details::None,
+        stack_frame: InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::zero(),
+            code_segment: 0,
+            cpu_flags: 0,
+            stack_pointer: VirtAddr::zero(),
+            stack_segment: 0,
+        },
+    }
+}
+
+// The fatal exceptions have a test of their own each (`exception_*`, `invalid_page_access` and
+// `stack_overflow`), since they end with a panic.
+
+#[test_case]
+fn breakpoint_resumes() {
+    x86_64::instructions::interrupts::int3();
+    let exception = exceptions::last_exception().unwrap();
+    assert_eq!(exception.vector, ExceptionVector::Breakpoint);
+    assert_eq!(exception.error_code, None);
+}
+
+#[test_case]
+fn debug_trap_resumes() {
+    // `int1` (or `icebp`), raising a #DB as a trap
+    unsafe { asm!(".byte 0xf1", options(nomem, nostack)) };
+    let exception = exceptions::last_exception().unwrap();
+    assert_eq!(exception.vector, ExceptionVector::Debug);
+}
+
+#[test_case]
+fn non_maskable_interrupt_resumes() {
+    unsafe { asm!("int 2", options(nomem, nostack)) };
+    let exception = exceptions::last_exception().unwrap();
+    assert_eq!(exception.vector, ExceptionVector::NonMaskableInterrupt);
+}
+
+#[test_case]
+fn selector_error_codes_are_decoded() {
+    // index 3 in the LDT, during an external event
+    let exception = fake_exception(
+        ExceptionVector::SegmentNotPresent,
+        Some((3 << 3) | 0b100 | 0b1),
+    );
+    let selector = exception.selector().unwrap();
+    assert_eq!(selector.index(), 3);
+    assert_eq!(selector.descriptor_table(), DescriptorTable::Ldt);
+    assert!(selector.external());
+    let report = format!("{}", exception);
+    assert!(report.contains("SEGMENT NOT PRESENT (#NP, vector 11)"));
+    assert!(report.contains("selector index 3 in the LDT, external event"));
+
+    // not related to a segment
+    let exception = fake_exception(ExceptionVector::GeneralProtection, Some(0));
+    assert!(exception.selector().is_none());
+    assert!(format!("{}", exception).contains("not segment related"));
+
+    // page faults don't have a selector
+    let exception = fake_exception(ExceptionVector::Page, Some(0b11));
+    assert!(exception.selector().is_none());
+}
+
+#[test_case]
+fn page_fault_report_has_the_address() {
+    let mut exception = fake_exception(ExceptionVector::Page, Some(0b10));
+    exception.details = Details::FaultAddress(VirtAddr::new(0xdead_b000));
+    let report = format!("{}", exception);
+    assert!(report.contains("CAUSED_BY_WRITE"));
+    assert!(report.contains("Accessed address: VirtAddr(0xdeadb000)"));
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator, exit_qemu,
+    memory::{self, BitmapFrameAllocator},
+    serial_print, serial_println, QemuExitCode,
+};
+use lazy_static::lazy_static;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+extern crate alloc;
+
+entry_point!(main);
+
+/// Address of the code copied to the heap
+static HEAP_CODE: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("execute_from_heap::execute_from_heap...\t");
+
+    clacos::gdt::init();
+    init_test_idt();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    // a lone `ret` instruction, which would return right away if the heap were executable
+    let code = Box::new([0xc3u8]);
+    HEAP_CODE.store(code.as_ptr() as u64, Ordering::Relaxed);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    panic!("Execution continued after executing from the heap");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let heap_code = HEAP_CODE.load(Ordering::Relaxed);
+    assert_eq!(Cr2::read().as_u64(), heap_code);
+    assert!(error_code.contains(
+        PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::PROTECTION_VIOLATION
+    ));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use clacos::{
+    allocator::{
+        linked_list::{FitPolicy, LinkedListAllocator},
+        HeapBackend, HeapStats, Locked,
+    },
+    serial_println,
+};
+
+extern crate alloc;
+
+/// Size of the memory area handed to the allocators under test
+const ARENA_SIZE: usize = 64 * 1024; // 64 KiB
+
+/// Backing memory for the allocators under test, page aligned like a real heap would be
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+/// Number of allocations done by `run_workload`
+const ITERATIONS: usize = 4096;
+/// One allocation out of this many is long-lived
+const LONG_LIVED_EVERY: usize = 64;
+/// Number of short-lived allocations alive at the same time
+const SHORT_LIVED_COUNT: usize = 16;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Run the `many_boxes_with_long_lived` pattern of `heap_allocation.rs` with varied sizes, some
+/// long-lived allocations along the way, and a few short-lived ones alive at the same time.
+///
+/// Return the state of the allocator at the end, with only the long-lived allocations left.
+fn run_workload(policy: FitPolicy) -> HeapStats {
+    let allocator = Locked::new(LinkedListAllocator::with_policy(policy));
+    unsafe {
+        let arena_start = core::ptr::addr_of_mut!(ARENA) as usize;
+        allocator.lock().init(arena_start, ARENA_SIZE);
+    }
+
+    let alloc = |layout: Layout| {
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null(), "{:?}: allocation of {:?} failed", policy, layout);
+        ptr
+    };
+
+    // like `long_lived` in `many_boxes_with_long_lived`, never freed
+    let _long_lived = alloc(Layout::new::<u64>());
+    let mut short_lived = [None; SHORT_LIVED_COUNT];
+    for i in 0..ITERATIONS {
+        let layout = Layout::from_size_align(16 + (i * 37) % 240, 8).unwrap();
+        let ptr = alloc(layout);
+        if i % LONG_LIVED_EVERY == 0 {
+            // never freed either
+            continue;
+        }
+        if let Some((ptr, layout)) = short_lived[i % SHORT_LIVED_COUNT].replace((ptr, layout)) {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+    for (ptr, layout) in short_lived.into_iter().flatten() {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    let stats = allocator.lock().stats();
+    // print the results, since they are what this benchmark is for
+    serial_println!(
+        "\n    {:?}: {} free regions, largest free block of {} out of {} free bytes \
+         ({}% fragmentation)",
+        policy,
+        stats.free_regions,
+        stats.largest_free_block,
+        stats.free,
+        fragmentation(&stats)
+    );
+    stats
+}
+
+/// Percentage of the free memory that can't be used by the largest possible allocation
+fn fragmentation(stats: &HeapStats) -> usize {
+    100 - 100 * stats.largest_free_block / stats.free
+}
+
+#[test_case]
+fn first_fit() {
+    run_workload(FitPolicy::FirstFit);
+}
+
+#[test_case]
+fn next_fit() {
+    run_workload(FitPolicy::NextFit);
+}
+
+#[test_case]
+fn best_fit() {
+    run_workload(FitPolicy::BestFit);
+}
+
+/// Check that best-fit, which is meant to limit fragmentation, does at least as well as next-fit,
+/// which spreads the allocations over the whole heap
+#[test_case]
+fn best_fit_fragments_less_than_next_fit() {
+    let next_fit = run_workload(FitPolicy::NextFit);
+    let best_fit = run_workload(FitPolicy::BestFit);
+    assert!(fragmentation(&best_fit) <= fragmentation(&next_fit));
+}
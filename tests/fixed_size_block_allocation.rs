@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use clacos::allocator::{fixed_size_block::FixedSizeBlockAllocator, Locked};
+
+extern crate alloc;
+
+/// Size of the memory area handed to the allocator under test
+const ARENA_SIZE: usize = 64 * 1024; // 64 KiB
+
+/// Backing memory for the allocator under test, page aligned like a real heap would be
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+/// The allocator under test (not the global one, which this test doesn't use)
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        let arena_start = core::ptr::addr_of_mut!(ARENA) as usize;
+        ALLOCATOR.lock().init(arena_start, ARENA_SIZE);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Allocate from the allocator under test, panicking when out of memory
+fn alloc(layout: Layout) -> *mut u8 {
+    let ptr = unsafe { ALLOCATOR.alloc(layout) };
+    assert!(!ptr.is_null(), "allocation of {:?} failed", layout);
+    ptr
+}
+
+fn dealloc(ptr: *mut u8, layout: Layout) {
+    unsafe { ALLOCATOR.dealloc(ptr, layout) }
+}
+
+/// Check that basic allocations works without error
+#[test_case]
+fn simple_allocation() {
+    let layout = Layout::new::<u64>();
+    let value_1 = alloc(layout) as *mut u64;
+    let value_2 = alloc(layout) as *mut u64;
+    unsafe {
+        value_1.write(41);
+        value_2.write(42);
+        assert_eq!(value_1.read(), 41);
+        assert_eq!(value_2.read(), 42);
+    }
+    dealloc(value_1 as *mut u8, layout);
+    dealloc(value_2 as *mut u8, layout);
+}
+
+/// Check that every size class returns correctly aligned blocks
+#[test_case]
+fn every_size_class() {
+    let mut size = 1;
+    while size <= 2048 {
+        let layout = Layout::from_size_align(size, size.min(64)).unwrap();
+        let ptr = alloc(layout);
+        assert_eq!(ptr as usize % layout.align(), 0);
+        unsafe { ptr.write_bytes(0xab, size) };
+        dealloc(ptr, layout);
+        size *= 2;
+    }
+}
+
+/// Check that a freed block is handed out again for any layout of the same size class
+#[test_case]
+fn blocks_are_reused() {
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let first = alloc(layout);
+    dealloc(first, layout);
+
+    // 24 bytes fall in the same 32-byte class
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let second = alloc(layout);
+    assert_eq!(first, second);
+    dealloc(second, layout);
+}
+
+/// Check that memory is correctly reclaimed for small allocations
+#[test_case]
+fn many_small_allocations() {
+    // do ARENA_SIZE allocations, which would overflow the arena if the blocks weren't reclaimed
+    // after each loop iteration
+    let layout = Layout::new::<usize>();
+    for i in 0..ARENA_SIZE {
+        let ptr = alloc(layout) as *mut usize;
+        unsafe {
+            ptr.write(i);
+            assert_eq!(ptr.read(), i);
+        }
+        dealloc(ptr as *mut u8, layout);
+    }
+}
+
+/// Check that allocations too large for any block go through the fallback allocator and are
+/// reclaimed too
+#[test_case]
+fn many_large_allocations() {
+    let layout = Layout::from_size_align(ARENA_SIZE / 4, 8).unwrap();
+    for _ in 0..ARENA_SIZE {
+        let ptr = alloc(layout);
+        unsafe { ptr.write_bytes(0xcd, layout.size()) };
+        dealloc(ptr, layout);
+    }
+}
+
+/// Check that small blocks kept alive don't prevent large allocations from using the rest of the
+/// arena
+#[test_case]
+fn large_allocation_with_long_lived_blocks() {
+    let small = Layout::new::<u64>();
+    let long_lived = alloc(small) as *mut u64;
+    unsafe { long_lived.write(u64::MAX) };
+
+    let large = Layout::from_size_align(ARENA_SIZE / 2, 8).unwrap();
+    for _ in 0..16 {
+        let ptr = alloc(large);
+        dealloc(ptr, large);
+    }
+
+    assert_eq!(unsafe { long_lived.read() }, u64::MAX);
+    dealloc(long_lived as *mut u8, small);
+}
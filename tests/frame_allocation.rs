@@ -0,0 +1,153 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{
+    bootinfo::{MemoryMap, MemoryRegionType},
+    entry_point, BootInfo,
+};
+use clacos::memory::BitmapFrameAllocator;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+/// The allocator under test, along with what it was built from
+struct TestState {
+    frame_allocator: BitmapFrameAllocator,
+    memory_map: &'static MemoryMap,
+    phys_mem_offset: VirtAddr,
+}
+
+static STATE: spin::Mutex<Option<TestState>> = spin::Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *STATE.lock() = Some(TestState {
+        frame_allocator,
+        memory_map: &boot_info.memory_map,
+        phys_mem_offset,
+    });
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Run `f` with the test state
+fn with_state<R>(f: impl FnOnce(&mut TestState) -> R) -> R {
+    f(STATE.lock().as_mut().unwrap())
+}
+
+/// Check whether the memory map says that `frame` is usable
+fn is_usable(memory_map: &MemoryMap, frame: PhysFrame) -> bool {
+    let addr = frame.start_address().as_u64();
+    memory_map.iter().any(|r| {
+        r.region_type == MemoryRegionType::Usable
+            && r.range.start_addr() <= addr
+            && addr < r.range.end_addr()
+    })
+}
+
+/// Check that the counts match the memory map, only the bitmap and reference counts being in use
+/// at first
+#[test_case]
+fn counts_match_memory_map() {
+    with_state(|state| {
+        let usable: u64 = state
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.end_frame_number - r.range.start_frame_number)
+            .sum();
+        let allocator = &state.frame_allocator;
+        assert_eq!(allocator.total_frames(), usable as usize);
+        assert!(allocator.used_frames() > 0);
+        assert_eq!(
+            allocator.free_frames(),
+            allocator.total_frames() - allocator.used_frames()
+        );
+    });
+}
+
+/// Check that an allocated frame is usable and counted, and reused once freed
+#[test_case]
+fn allocate_and_free() {
+    with_state(|state| {
+        let used = state.frame_allocator.used_frames();
+        let frame = state.frame_allocator.allocate_frame().unwrap();
+        assert!(is_usable(state.memory_map, frame));
+        assert_eq!(state.frame_allocator.used_frames(), used + 1);
+
+        // the frame can be written through the physical memory mapping
+        let virt = state.phys_mem_offset + frame.start_address().as_u64();
+        let ptr: *mut u64 = virt.as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(0xdead_beef);
+            assert_eq!(ptr.read_volatile(), 0xdead_beef);
+        }
+
+        unsafe { state.frame_allocator.deallocate_frame(frame) };
+        assert_eq!(state.frame_allocator.used_frames(), used);
+        assert_eq!(state.frame_allocator.allocate_frame(), Some(frame));
+        unsafe { state.frame_allocator.deallocate_frame(frame) };
+    });
+}
+
+/// Check that many frames can be allocated, are all different and can all be freed
+#[test_case]
+fn many_frames() {
+    const COUNT: usize = 1000;
+    with_state(|state| {
+        let used = state.frame_allocator.used_frames();
+        let mut frames = [None; COUNT];
+        for slot in frames.iter_mut() {
+            *slot = state.frame_allocator.allocate_frame();
+        }
+        assert_eq!(state.frame_allocator.used_frames(), used + COUNT);
+        for (i, frame) in frames.iter().enumerate() {
+            let frame = frame.unwrap();
+            assert!(is_usable(state.memory_map, frame));
+            assert!(frames[..i].iter().all(|&other| other != Some(frame)));
+        }
+
+        for frame in frames {
+            unsafe { state.frame_allocator.deallocate_frame(frame.unwrap()) };
+        }
+        assert_eq!(state.frame_allocator.used_frames(), used);
+    });
+}
+
+/// Check that frames freed out of order are all found again
+#[test_case]
+fn frames_freed_in_the_middle_are_reused() {
+    with_state(|state| {
+        let frames: [PhysFrame; 3] =
+            core::array::from_fn(|_| state.frame_allocator.allocate_frame().unwrap());
+        unsafe {
+            state.frame_allocator.deallocate_frame(frames[1]);
+            state.frame_allocator.deallocate_frame(frames[0]);
+        }
+        let first = state.frame_allocator.allocate_frame().unwrap();
+        let second = state.frame_allocator.allocate_frame().unwrap();
+        assert!(first == frames[0] || first == frames[1]);
+        assert!(second == frames[0] || second == frames[1]);
+        assert_ne!(first, second);
+        for frame in [first, second, frames[2]] {
+            unsafe { state.frame_allocator.deallocate_frame(frame) };
+        }
+    });
+}
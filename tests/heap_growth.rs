@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    boxed::Box,
+    vec::Vec,
+};
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE},
+    memory::{self, BitmapFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // initialize the kernel and its memory handling, allowing the heap to grow
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Check that a single allocation larger than the initial heap succeeds
+#[test_case]
+fn allocation_larger_than_initial_heap() {
+    let n = 4 * HEAP_SIZE;
+    let mut vec: Vec<u8> = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u8);
+    }
+    assert!(vec.iter().enumerate().all(|(i, &x)| x == i as u8));
+    assert!(allocator::heap_size() > HEAP_SIZE);
+}
+
+/// Check that many live allocations can take more memory than the initial heap
+#[test_case]
+fn many_long_lived_boxes() {
+    let count = 2 * HEAP_SIZE / 1024;
+    let boxes: Vec<Box<[u8; 1024]>> = (0..count).map(|i| Box::new([i as u8; 1024])).collect();
+    for (i, b) in boxes.iter().enumerate() {
+        assert!(b.iter().all(|&x| x == i as u8));
+    }
+}
+
+/// Check that the heap doesn't grow beyond its maximum size
+#[test_case]
+fn allocation_larger_than_max_size_fails() {
+    // use a lower limit than the default one to keep the test fast
+    let max_size = 4 * 1024 * 1024;
+    allocator::set_max_heap_size(max_size);
+
+    let layout = Layout::from_size_align(max_size, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(ptr.is_null());
+    assert!(allocator::heap_size() <= max_size);
+
+    allocator::set_max_heap_size(HEAP_MAX_SIZE);
+}
+
+/// Check that lowering the maximum size stops the heap from growing
+#[test_case]
+fn max_size_is_configurable() {
+    let size = allocator::heap_size();
+    allocator::set_max_heap_size(size);
+
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(ptr.is_null());
+    assert_eq!(allocator::heap_size(), size);
+
+    // grow again once the limit is raised
+    allocator::set_max_heap_size(HEAP_MAX_SIZE);
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { dealloc(ptr, layout) };
+}
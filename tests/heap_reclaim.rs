@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    vec::Vec,
+};
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator::{self, HEAP_MAX_SIZE},
+    memory::{self, BitmapFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Size of the buffers kept in `CACHE`
+const BUFFER_LAYOUT: Layout = match Layout::from_size_align(16 * 1024, 8) {
+    Ok(layout) => layout,
+    Err(_) => panic!("invalid layout"),
+};
+
+/// Addresses of buffers that the reclaim handler can free, like a cache would
+static CACHE: spin::Mutex<Vec<usize>> = spin::Mutex::new(Vec::new());
+
+/// Free one cached buffer at a time, so that the allocation may be retried several times
+fn free_one_cached_buffer(_layout: Layout) -> bool {
+    match CACHE.lock().pop() {
+        Some(buffer) => {
+            unsafe { dealloc(buffer as *mut u8, BUFFER_LAYOUT) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Check that an allocation failing because the cache holds the memory succeeds once the cache
+/// has been reclaimed
+#[test_case]
+fn reclaim_handler_frees_memory() {
+    // fill the heap with cached buffers, without letting it grow
+    CACHE.lock().reserve(1024);
+    allocator::set_max_heap_size(allocator::heap_size());
+    loop {
+        let buffer = unsafe { alloc(BUFFER_LAYOUT) };
+        if buffer.is_null() {
+            break;
+        }
+        CACHE.lock().push(buffer as usize);
+    }
+    let count = CACHE.lock().len();
+    assert!(count > 0);
+
+    allocator::set_reclaim_handler(Some(free_one_cached_buffer));
+    let ptr = unsafe { alloc(BUFFER_LAYOUT) };
+    assert!(!ptr.is_null());
+    assert!(CACHE.lock().len() < count);
+
+    unsafe { dealloc(ptr, BUFFER_LAYOUT) };
+    while free_one_cached_buffer(BUFFER_LAYOUT) {}
+    allocator::set_reclaim_handler(None);
+    allocator::set_max_heap_size(HEAP_MAX_SIZE);
+}
+
+/// Check that a reclaim handler that can't free anything doesn't make the allocation loop
+#[test_case]
+fn failing_reclaim_handler() {
+    fn reclaim_nothing(_layout: Layout) -> bool {
+        false
+    }
+
+    let size = allocator::heap_size();
+    allocator::set_max_heap_size(size);
+    allocator::set_reclaim_handler(Some(reclaim_nothing));
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    allocator::set_reclaim_handler(None);
+    allocator::set_max_heap_size(HEAP_MAX_SIZE);
+
+    assert!(ptr.is_null());
+}
+
+/// Check that a reclaim handler claiming to free memory without doing so doesn't make the
+/// allocation loop
+#[test_case]
+fn lying_reclaim_handler() {
+    fn reclaim_nothing_but_claim_to(_layout: Layout) -> bool {
+        true
+    }
+
+    let size = allocator::heap_size();
+    allocator::set_max_heap_size(size);
+    allocator::set_reclaim_handler(Some(reclaim_nothing_but_claim_to));
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    allocator::set_reclaim_handler(None);
+    allocator::set_max_heap_size(HEAP_MAX_SIZE);
+
+    assert!(ptr.is_null());
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    exit_qemu,
+    memory::{self, BitmapFrameAllocator},
+    serial_print, serial_println, QemuExitCode,
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("huge_frame_shared::should_fail...\t");
+
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    // freeing a huge frame must not free a 4 KiB frame of it that is still shared
+    let frame: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+    let sub_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(frame.start_address());
+    frame_allocator.add_reference(sub_frame);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
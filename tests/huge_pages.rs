@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    memory::{self, vm, BitmapFrameAllocator},
+};
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+        Translate,
+    },
+    VirtAddr,
+};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Return the size of the page mapping `addr`, if mapped
+fn page_size(addr: VirtAddr) -> Option<u64> {
+    memory::with_global_paging(|mapper, _| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, .. } => match frame {
+            MappedFrame::Size4KiB(_) => Some(Size4KiB::SIZE),
+            MappedFrame::Size2MiB(_) => Some(Size2MiB::SIZE),
+            MappedFrame::Size1GiB(_) => Some(1 << 30),
+        },
+        _ => None,
+    })
+    .unwrap()
+}
+
+/// Check that huge frames are aligned and counted as 512 frames
+#[test_case]
+fn allocate_huge_frame() {
+    memory::with_global_paging(|_, frame_allocator| {
+        let used = frame_allocator.used_frames();
+        let frame: PhysFrame<Size2MiB> = frame_allocator.allocate_frame().unwrap();
+        assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+        assert_eq!(frame_allocator.used_frames(), used + 512);
+
+        // the 4 KiB frames don't overlap it
+        let small: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+        let huge_range = frame.start_address()..frame.start_address() + Size2MiB::SIZE;
+        assert!(!huge_range.contains(&small.start_address()));
+
+        unsafe {
+            frame_allocator.deallocate_frame(small);
+            frame_allocator.deallocate_frame(frame);
+        }
+        assert_eq!(frame_allocator.used_frames(), used);
+    })
+    .unwrap();
+}
+
+/// Check that a range is mapped with huge pages where it is aligned for them, and 4 KiB pages
+/// around
+#[test_case]
+fn map_range_uses_huge_pages() {
+    // a range starting 4 KiB before a 2 MiB boundary, in an unused region
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = vm::reserve(
+        3 * Size2MiB::SIZE,
+        Size2MiB::SIZE,
+        flags,
+        vm::RegionKind::Other,
+    )
+    .expect("reservation failed");
+    let boundary = region.start() + Size2MiB::SIZE;
+    let start = boundary - Size4KiB::SIZE;
+    let size = Size4KiB::SIZE + 2 * Size2MiB::SIZE + Size4KiB::SIZE;
+    memory::with_global_paging(|mapper, frame_allocator| {
+        memory::map_range(start, size, flags, mapper, frame_allocator)
+    })
+    .unwrap()
+    .expect("mapping failed");
+
+    assert_eq!(page_size(start), Some(Size4KiB::SIZE));
+    assert_eq!(page_size(boundary), Some(Size2MiB::SIZE));
+    assert_eq!(page_size(boundary + Size2MiB::SIZE), Some(Size2MiB::SIZE));
+    assert_eq!(page_size(start + size - 1u64), Some(Size4KiB::SIZE));
+    assert_eq!(page_size(start + size), None);
+
+    // the whole range is usable
+    for offset in (0..size).step_by(Size4KiB::SIZE as usize) {
+        let ptr: *mut u64 = (start + offset).as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(offset);
+            assert_eq!(ptr.read_volatile(), offset);
+        }
+    }
+}
+
+/// Check that the heap uses huge pages once it has grown past a 2 MiB boundary
+#[test_case]
+fn heap_grows_with_huge_pages() {
+    let vec: Vec<u8> = Vec::with_capacity(8 * 1024 * 1024);
+    assert!(vec.capacity() > 0);
+
+    let heap_start = VirtAddr::new(allocator::heap_start() as u64);
+    let heap_end = heap_start + allocator::heap_size();
+    let first_boundary = heap_start.align_up(Size2MiB::SIZE);
+    let huge_pages = (first_boundary.as_u64()..heap_end.as_u64())
+        .step_by(Size2MiB::SIZE as usize)
+        .filter(|&addr| page_size(VirtAddr::new(addr)) == Some(Size2MiB::SIZE))
+        .count();
+    assert!(huge_pages > 0);
+}
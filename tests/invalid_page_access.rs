@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator, exit_qemu,
+    memory::{
+        self,
+        vm::{self, RegionKind},
+        BitmapFrameAllocator,
+    },
+    serial_print, serial_println, QemuExitCode,
+};
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("invalid_page_access::write_to_read_only_lazy_region...\t");
+
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    // demand paging must not map pages the region doesn't allow to write to, so the page fault
+    // is fatal
+    let region = vm::reserve_lazy(
+        Size4KiB::SIZE,
+        1,
+        PageTableFlags::PRESENT,
+        RegionKind::Other,
+    )
+    .unwrap();
+    let ptr: *mut u64 = region.start().as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,129 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator, apic,
+    interrupts::{self, InterruptIndex, IrqError, IRQ_LINES},
+    memory::{self, BitmapFrameAllocator},
+};
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// A line with no device in QEMU, only raised by software here (see `raise_free_line`)
+const FREE_LINE: u8 = 5;
+
+/// Raise the vector of `FREE_LINE` from software
+fn raise_free_line() {
+    const _: () = assert!(interrupts::PIC_1_OFFSET + FREE_LINE == 37);
+    without_interrupts(|| unsafe { asm!("int 37") });
+}
+
+static FREE_LINE_CALLS: AtomicUsize = AtomicUsize::new(0);
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_free_line() {
+    FREE_LINE_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn count_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Wait for `count` timer ticks counted by `count_tick`
+fn wait_ticks(count: usize) {
+    let start = TICKS.load(Ordering::Relaxed);
+    // a tick wakes the CPU up, so this hangs if they aren't dispatched or acknowledged
+    while TICKS.load(Ordering::Relaxed) < start + count {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn invalid_lines_are_rejected() {
+    assert_eq!(
+        interrupts::register_irq(IRQ_LINES as u8, count_free_line),
+        Err(IrqError::InvalidLine(IRQ_LINES as u8))
+    );
+    assert!(interrupts::unregister_irq(IRQ_LINES as u8).is_none());
+}
+
+#[test_case]
+fn lines_have_a_single_handler() {
+    let timer = InterruptIndex::Timer.irq();
+    assert_eq!(
+        interrupts::register_irq(timer, count_tick),
+        Err(IrqError::AlreadyRegistered(timer))
+    );
+    assert!(interrupts::registered_irqs().any(|line| line == timer));
+    assert!(interrupts::registered_irqs().any(|line| line == InterruptIndex::Keyboard.irq()));
+}
+
+#[test_case]
+fn registered_handler_is_called() {
+    interrupts::register_irq(FREE_LINE, count_free_line).unwrap();
+    assert!(interrupts::registered_irqs().any(|line| line == FREE_LINE));
+
+    raise_free_line();
+    assert_eq!(FREE_LINE_CALLS.load(Ordering::Relaxed), 1);
+
+    // not called anymore once unregistered
+    assert!(interrupts::unregister_irq(FREE_LINE).is_some());
+    raise_free_line();
+    assert_eq!(FREE_LINE_CALLS.load(Ordering::Relaxed), 1);
+    assert!(interrupts::unregister_irq(FREE_LINE).is_none());
+}
+
+#[test_case]
+fn timer_handler_can_be_replaced() {
+    let timer = InterruptIndex::Timer.irq();
+    let previous = interrupts::unregister_irq(timer).expect("no timer handler");
+    interrupts::register_irq(timer, count_tick).unwrap();
+
+    // the end of interrupt is signaled for the handler, or there would be a single tick
+    wait_ticks(3);
+
+    interrupts::unregister_irq(timer);
+    interrupts::register_irq(timer, previous).unwrap();
+}
+
+#[test_case]
+fn handlers_are_kept_with_the_apic() {
+    let timer = InterruptIndex::Timer.irq();
+    let previous = interrupts::unregister_irq(timer).unwrap();
+    interrupts::register_irq(timer, count_tick).unwrap();
+
+    apic::init().expect("APIC initialization failed");
+    wait_ticks(3);
+
+    interrupts::unregister_irq(timer);
+    interrupts::register_irq(timer, previous).unwrap();
+}
@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    memory::{
+        self, dump,
+        protection::{self, KernelSegment},
+        stack, BitmapFrameAllocator,
+    },
+};
+use x86_64::{
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+extern crate alloc;
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Some read-only data, which ends up in `.rodata`
+static RODATA: [u64; 4] = [1, 2, 3, 4];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Return the effective flags of the page mapping `addr`
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    memory::with_global_paging(|mapper, _| dump::walk(mapper, addr))
+        .unwrap()
+        .flags
+        .expect("address not mapped")
+}
+
+fn is_writable(addr: VirtAddr) -> bool {
+    flags_of(addr).contains(PageTableFlags::WRITABLE)
+}
+
+fn is_executable(addr: VirtAddr) -> bool {
+    !flags_of(addr).contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// Check that NX is enabled
+#[test_case]
+fn nx_enabled() {
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+/// Check that no kernel segment is both writable and executable, and that there is code, read-only
+/// data and writable data
+#[test_case]
+fn kernel_segments_are_wx() {
+    let segments = protection::kernel_segments;
+    assert!(segments().all(|segment| !(segment.writable && segment.executable)));
+    assert!(segments().any(|segment| segment.executable));
+    assert!(segments().any(|segment| !segment.writable && !segment.executable));
+    assert!(segments().any(|segment| segment.writable));
+}
+
+/// Check that an empty segment has no page to remap, and that the pages of the others cover them
+#[test_case]
+fn segment_pages() {
+    let segment = KernelSegment {
+        start: VirtAddr::new(0x20_0000),
+        size: 0,
+        writable: true,
+        executable: false,
+    };
+    assert!(segment.pages().is_none());
+
+    let segment = KernelSegment {
+        size: 0x1001,
+        ..segment
+    };
+    let pages = segment.pages().unwrap();
+    assert_eq!(pages.start.start_address(), VirtAddr::new(0x20_0000));
+    assert_eq!(pages.end.start_address(), VirtAddr::new(0x20_1000));
+}
+
+/// Check that the code is read-only and executable
+#[test_case]
+fn text_is_read_only_executable() {
+    let addr = VirtAddr::new(flags_of as usize as u64);
+    assert!(!is_writable(addr));
+    assert!(is_executable(addr));
+}
+
+/// Check that read-only data is read-only and not executable
+#[test_case]
+fn rodata_is_read_only_nx() {
+    let addr = VirtAddr::from_ptr(&RODATA);
+    assert_eq!(RODATA[2], 3);
+    assert!(!is_writable(addr));
+    assert!(!is_executable(addr));
+}
+
+/// Check that writable data is not executable
+#[test_case]
+fn data_is_nx() {
+    let addr = VirtAddr::from_ptr(&PHYS_MEM_OFFSET);
+    assert!(is_writable(addr));
+    assert!(!is_executable(addr));
+}
+
+/// Check that the heap is not executable
+#[test_case]
+fn heap_is_nx() {
+    let value = Box::new(0u64);
+    let addr = VirtAddr::from_ptr(&*value);
+    assert!(is_writable(addr));
+    assert!(!is_executable(addr));
+}
+
+/// Check that the stacks from `memory::stack` are not executable
+#[test_case]
+fn stacks_are_nx() {
+    let stack = stack::allocate_stack(2).unwrap();
+    assert!(is_writable(stack.bottom()));
+    assert!(!is_executable(stack.bottom()));
+    unsafe { stack::free_stack(stack) };
+}
+
+/// Check that the physical memory mapping is not executable
+#[test_case]
+fn physical_memory_is_nx() {
+    let addr = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed) + 0x1000);
+    assert!(!is_executable(addr));
+}
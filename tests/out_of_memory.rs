@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator, exit_qemu,
+    memory::{self, BitmapFrameAllocator},
+    serial_print, serial_println, QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("out_of_memory::infallible_allocation_panics...\t");
+
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    // an infallible allocation larger than the heap may ever be must go through the allocation
+    // error handler (after the heap state is reported)
+    let max_size = 1024 * 1024;
+    allocator::set_max_heap_size(max_size);
+    let vec: Vec<u8> = Vec::with_capacity(2 * max_size);
+
+    serial_println!("[test did not panic]");
+    serial_println!("allocated {} bytes", vec.capacity());
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
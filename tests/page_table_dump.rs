@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    memory::{
+        self,
+        dump::{self, Mapping},
+        vm::{self, RegionKind},
+        BitmapFrameAllocator,
+    },
+};
+use x86_64::{
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB, Translate},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// Check that the walk of a heap address agrees with `translate_addr`
+#[test_case]
+fn walk_heap_address() {
+    let addr = VirtAddr::new(allocator::heap_start() as u64 + 0x123);
+    let (walk, translated) = memory::with_global_paging(|mapper, _| {
+        (dump::walk(mapper, addr), mapper.translate_addr(addr))
+    })
+    .unwrap();
+
+    assert_eq!(walk.phys, translated);
+    assert_eq!(walk.page_size(), Some(Size4KiB::SIZE));
+    let levels: [u8; 4] = walk.steps.map(|step| step.unwrap().level);
+    assert_eq!(levels, [4, 3, 2, 1]);
+    let flags = walk.flags.unwrap();
+    assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+/// Check that the walk of an unmapped address stops at the first entry that isn't present
+#[test_case]
+fn walk_unmapped_address() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let region = vm::reserve(Size4KiB::SIZE, 1, flags, RegionKind::Other).unwrap();
+    let walk = memory::with_global_paging(|mapper, _| dump::walk(mapper, region.start())).unwrap();
+
+    assert_eq!(walk.phys, None);
+    assert_eq!(walk.flags, None);
+    assert_eq!(walk.page_size(), None);
+    let last = walk.steps.iter().flatten().last().unwrap();
+    assert!(!last.flags.contains(PageTableFlags::PRESENT));
+    assert!(vm::release(&region));
+}
+
+/// Check that the physical memory mapping translates to the matching physical address
+#[test_case]
+fn walk_physical_memory_mapping() {
+    let phys_mem_offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+    let addr = VirtAddr::new(phys_mem_offset + 0x1234);
+    let walk = memory::with_global_paging(|mapper, _| dump::walk(mapper, addr)).unwrap();
+    assert_eq!(walk.phys, Some(PhysAddr::new(0x1234)));
+}
+
+/// Check that contiguous pages are coalesced into a single mapping, and that the mappings are
+/// sorted and disjoint
+#[test_case]
+fn mappings_are_coalesced() {
+    // 8 pages mapped to contiguous physical memory, the VGA buffer and what follows
+    let phys = PhysAddr::new(0xb8000);
+    let pages = 8;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vm::reserve(pages * Size4KiB::SIZE, 1, flags, RegionKind::Mmio).unwrap();
+
+    let mut found: Option<Mapping> = None;
+    let mut previous: Option<Mapping> = None;
+    memory::with_global_paging(|mapper, frame_allocator| {
+        unsafe { region.map_to_phys(phys, mapper, frame_allocator) }.expect("mapping failed");
+        dump::for_each_mapping(mapper, |mapping| {
+            if let Some(previous) = previous {
+                assert!(previous.virt_end() <= mapping.virt_start);
+            }
+            previous = Some(*mapping);
+            if mapping.virt_start <= region.start() && region.end() <= mapping.virt_end() {
+                found = Some(*mapping);
+            }
+        });
+        unsafe { region.unmap(mapper, frame_allocator) };
+    })
+    .unwrap();
+    assert!(vm::release(&region));
+
+    let mapping = found.expect("region not in the mappings");
+    assert_eq!(
+        mapping.phys_start + (region.start() - mapping.virt_start),
+        phys
+    );
+    assert_eq!(
+        mapping.flags,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+    );
+}
+
+/// Check that the PAT bit of a 4 KiB page, which is the `HUGE_PAGE` bit of the upper levels, isn't
+/// shown as a huge page
+#[test_case]
+fn pat_bit_is_not_huge_page() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vm::reserve(Size4KiB::SIZE, 1, flags, RegionKind::Mmio).unwrap();
+    let page = Page::<Size4KiB>::containing_address(region.start());
+
+    let mut found: Option<Mapping> = None;
+    let walk = memory::with_global_paging(|mapper, frame_allocator| {
+        unsafe { region.map_to_phys(PhysAddr::new(0xb8000), mapper, frame_allocator) }
+            .expect("mapping failed");
+        // PAT entry 4, which is write-back like entry 0 by default
+        unsafe { mapper.update_flags(page, flags | PageTableFlags::HUGE_PAGE) }
+            .unwrap()
+            .flush();
+        let walk = dump::walk(mapper, region.start());
+        dump::for_each_mapping(mapper, |mapping| {
+            if mapping.virt_start <= region.start() && region.start() < mapping.virt_end() {
+                found = Some(*mapping);
+            }
+        });
+        unsafe { region.unmap(mapper, frame_allocator) };
+        walk
+    })
+    .unwrap();
+    assert!(vm::release(&region));
+
+    let last = walk.steps[3].unwrap();
+    assert!(last.flags.contains(PageTableFlags::HUGE_PAGE));
+    assert_eq!(walk.page_size(), Some(Size4KiB::SIZE));
+    assert_eq!(walk.flags, Some(flags));
+    assert_eq!(found.expect("region not in the mappings").flags, flags);
+}
+
+/// Check that dumping the page tables works
+#[test_case]
+fn dump_page_tables() {
+    memory::with_global_paging(|mapper, _| dump::dump(mapper)).unwrap();
+}
@@ -0,0 +1,207 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator::{self, slab::SlabCache},
+    memory::{self, BitmapFrameAllocator},
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // initialize the kernel and its memory handling, the slabs taking their frames from the
+    // global frame allocator
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+/// A kernel object of a typical size
+struct Task {
+    id: u64,
+    registers: [u64; 16],
+}
+
+static TASKS: SlabCache<Task> = SlabCache::new("tasks");
+
+/// Check that basic allocations works without error
+#[test_case]
+fn simple_allocation() {
+    let mut task = TASKS
+        .alloc(Task {
+            id: 1,
+            registers: [0; 16],
+        })
+        .unwrap();
+    task.registers[3] = 42;
+    assert_eq!(task.id, 1);
+    assert_eq!(task.registers[3], 42);
+}
+
+/// Check that a freed slot is handed out again
+#[test_case]
+fn slots_are_reused() {
+    let task = TASKS
+        .alloc(Task {
+            id: 1,
+            registers: [0; 16],
+        })
+        .unwrap();
+    let addr = &*task as *const Task;
+    drop(task);
+
+    let task = TASKS
+        .alloc(Task {
+            id: 2,
+            registers: [0; 16],
+        })
+        .unwrap();
+    assert_eq!(&*task as *const Task, addr);
+}
+
+/// Check that allocating more objects than a slab holds takes another slab
+#[test_case]
+fn many_objects() {
+    static CACHE: SlabCache<Task> = SlabCache::new("many_objects");
+
+    let per_slab = CACHE.stats().objects_per_slab;
+    let tasks: Vec<_> = (0..2 * per_slab as u64)
+        .map(|id| {
+            CACHE
+                .alloc(Task {
+                    id,
+                    registers: [id; 16],
+                })
+                .unwrap()
+        })
+        .collect();
+    for (id, task) in tasks.iter().enumerate() {
+        assert_eq!(task.id, id as u64);
+        assert!(task.registers.iter().all(|&r| r == id as u64));
+    }
+
+    let stats = CACHE.stats();
+    assert_eq!(stats.slabs, 2);
+    assert_eq!(stats.used_objects, 2 * per_slab);
+    assert_eq!(stats.free_objects, 0);
+
+    // one empty slab is kept for later allocations, the other one gives its frame back
+    let used_frames = || memory::with_global_paging(|_, frames| frames.used_frames()).unwrap();
+    let before = used_frames();
+    drop(tasks);
+    assert_eq!(used_frames(), before - 1);
+    let stats = CACHE.stats();
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(stats.used_objects, 0);
+    assert_eq!(stats.free_objects, per_slab);
+
+    // and a slab is taken again when the kept one is full
+    let tasks: Vec<_> = (0..per_slab as u64 + 1)
+        .map(|id| {
+            CACHE
+                .alloc(Task {
+                    id,
+                    registers: [id; 16],
+                })
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(CACHE.stats().slabs, 2);
+    assert_eq!(used_frames(), before);
+    drop(tasks);
+}
+
+/// Check that the statistics are kept per cache
+#[test_case]
+fn stats_are_per_cache() {
+    static FIRST: SlabCache<u64> = SlabCache::new("first");
+    static SECOND: SlabCache<[u8; 100]> = SlabCache::new("second");
+
+    let _a = FIRST.alloc(1).unwrap();
+    let _b = FIRST.alloc(2).unwrap();
+    let c = SECOND.alloc([0; 100]).unwrap();
+    drop(c);
+
+    let first = FIRST.stats();
+    assert_eq!(first.name, "first");
+    assert_eq!(first.object_size, 8);
+    assert_eq!((first.allocations, first.deallocations), (2, 0));
+    assert_eq!(first.used_objects, 2);
+
+    let second = SECOND.stats();
+    assert_eq!(second.name, "second");
+    assert_eq!(second.object_size, 104);
+    assert_eq!((second.allocations, second.deallocations), (1, 1));
+    assert_eq!(second.used_objects, 0);
+}
+
+/// Check that values are dropped when their slot is freed
+#[test_case]
+fn values_are_dropped() {
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Droppable;
+    impl Drop for Droppable {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    static CACHE: SlabCache<Droppable> = SlabCache::new("droppable");
+
+    let value = CACHE.alloc(Droppable).unwrap();
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+    drop(value);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+}
+
+/// Check that objects are aligned as their type requires
+#[test_case]
+fn aligned_objects() {
+    #[repr(align(256))]
+    struct Aligned(u8);
+    static CACHE: SlabCache<Aligned> = SlabCache::new("aligned");
+
+    let values: [_; 8] = core::array::from_fn(|i| CACHE.alloc(Aligned(i as u8)).unwrap());
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(&**value as *const Aligned as usize % 256, 0);
+        assert_eq!(value.0, i as u8);
+    }
+}
+
+/// Check that the caches don't take memory from the heap
+#[test_case]
+fn heap_is_not_used() {
+    let before = allocator::heap_stats().allocations;
+    let task = TASKS
+        .alloc(Task {
+            id: 3,
+            registers: [0; 16],
+        })
+        .unwrap();
+    assert_eq!(allocator::heap_stats().allocations, before);
+    drop(task);
+}
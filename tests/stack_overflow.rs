@@ -2,29 +2,101 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
-use clacos::{exit_qemu, serial_print, serial_println, QemuExitCode};
-use core::panic::PanicInfo;
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    exit_qemu,
+    memory::{
+        self,
+        stack::{self, KernelStack},
+        vm::{self, RegionKind},
+        BitmapFrameAllocator,
+    },
+    serial_print, serial_println, QemuExitCode,
+};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{
+    registers::control::Cr2,
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame},
+        paging::{PageSize, Size4KiB},
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+// This test overflows two stacks in turn, each overflow ending in the double fault handler:
+// - the boot stack, with the double fault handler on the boot interrupt stack
+// - a stack from `memory::stack`, with the double fault handler on an interrupt stack from
+//   `memory::stack` too
+
+/// Number of double faults so far
+static DOUBLE_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Start address of the guard page of the stack overflowed second
+static GUARD_PAGE: AtomicU64 = AtomicU64::new(0);
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
     clacos::gdt::init();
     init_test_idt();
+    // the interrupt stack stays the boot one until `init_stacks`
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::make_global(mapper, frame_allocator);
 
     stack_overflow();
 
     panic!("Execution continued after stack overflow");
 }
 
+/// Second part of the test, run from the double fault handler
+fn runtime_stack_overflow() -> ! {
+    serial_print!("stack_overflow::runtime_stack_overflow...\t");
+
+    clacos::gdt::init_stacks();
+
+    let stack = stack::allocate_stack(4).expect("stack allocation failed");
+    GUARD_PAGE.store(
+        stack.guard_page().start_address().as_u64(),
+        Ordering::Relaxed,
+    );
+    run_on(&stack, overflow_current_stack)
+}
+
 #[allow(unconditional_recursion)]
 fn stack_overflow() {
     stack_overflow();
     volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
 }
 
+extern "C" fn overflow_current_stack() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+/// Switch to `stack` and call `f` on it
+fn run_on(stack: &KernelStack, f: extern "C" fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {f}",
+            top = in(reg) stack.top().as_u64(),
+            f = in(reg) f,
+            options(noreturn),
+        )
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     clacos::test_panic_handler(info)
@@ -46,6 +118,29 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    if DOUBLE_FAULTS.fetch_add(1, Ordering::Relaxed) == 0 {
+        serial_println!("[ok]");
+        // the interrupt stack is reset on every double fault, so the frames of this handler can
+        // be abandoned
+        runtime_stack_overflow();
+    }
+
+    // the overflow hit the guard page of the stack
+    let guard_page = GUARD_PAGE.load(Ordering::Relaxed);
+    let fault_addr = Cr2::read().as_u64();
+    assert!(
+        (guard_page..guard_page + Size4KiB::SIZE).contains(&fault_addr),
+        "fault at {:#x} outside of the guard page at {:#x}",
+        fault_addr,
+        guard_page
+    );
+
+    // and this handler runs on a runtime-allocated stack too
+    let local = 0u8;
+    let region = vm::region_at(VirtAddr::from_ptr(&local)).expect("handler stack not in a region");
+    assert_eq!(region.kind(), RegionKind::Stack);
+    assert!(!region.contains(VirtAddr::new(guard_page)));
+
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
     loop {}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{panic::PanicInfo, time::Duration};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator,
+    memory::{self, BitmapFrameAllocator},
+    time::{self, ClockSource, TICK_HZ},
+};
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+const SLEEP: Duration = Duration::from_millis(50);
+
+/// Check that the clock is monotonic, and that sleeping takes at least as long as asked and the
+/// timer interrupts keep coming.
+///
+/// Emulation can delay the wake-up and the interrupts a lot, so the upper bounds are only there to
+/// catch a clock or a timer running wild.
+fn check_clock() {
+    let mut last = time::uptime();
+    for _ in 0..1000 {
+        let now = time::uptime();
+        assert!(now >= last, "uptime went from {:?} back to {:?}", last, now);
+        last = now;
+    }
+
+    let start = time::uptime();
+    let start_ticks = time::ticks();
+    time::sleep(SLEEP);
+    let slept = time::uptime() - start;
+    let ticks = time::ticks() - start_ticks;
+
+    assert!(slept >= SLEEP, "slept only {:?}", slept);
+    assert!(slept < SLEEP * 20, "slept {:?}", slept);
+    let expected_ticks = SLEEP.as_millis() as u64 * TICK_HZ / 1000;
+    assert!(
+        (1..=expected_ticks * 10).contains(&ticks),
+        "{} ticks in {:?}",
+        ticks,
+        slept
+    );
+}
+
+#[test_case]
+fn pit_clock() {
+    assert_eq!(time::source(), ClockSource::Pit);
+    check_clock();
+}
+
+#[test_case]
+fn pit_sleep_without_interrupts() {
+    let start_ticks = time::ticks();
+    without_interrupts(|| time::sleep(SLEEP));
+    // the timer interrupt, pending at most once, can't have been counted
+    assert!(time::ticks() - start_ticks <= 1);
+}
+
+#[test_case]
+fn hpet_clock() {
+    let before = time::uptime();
+    time::init_hpet().expect("HPET initialization failed");
+    assert_eq!(time::source(), ClockSource::Hpet);
+    assert!(time::uptime() >= before);
+    check_clock();
+}
+
+#[test_case]
+fn hpet_sleep_without_interrupts() {
+    let start = time::uptime();
+    without_interrupts(|| time::sleep(SLEEP));
+    assert!(time::uptime() - start >= SLEEP);
+}
@@ -0,0 +1,216 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(clacos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use clacos::{
+    allocator::{self, HEAP_RESERVED_SIZE},
+    memory::{
+        self,
+        vm::{self, Region, RegionKind, KERNEL_SPACE_END, KERNEL_SPACE_START},
+        BitmapFrameAllocator,
+    },
+};
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB, Translate},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    clacos::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::make_global(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    clacos::test_panic_handler(info);
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+/// Return the number of frames in use
+fn used_frames() -> usize {
+    memory::with_global_paging(|_, frame_allocator| frame_allocator.used_frames()).unwrap()
+}
+
+/// Check whether `addr` is mapped
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_global_paging(|mapper, _| mapper.translate_addr(addr).is_some()).unwrap()
+}
+
+/// Map `region` to new frames
+fn map(region: &Region) {
+    memory::with_global_paging(|mapper, frame_allocator| region.map(mapper, frame_allocator))
+        .unwrap()
+        .expect("mapping failed");
+}
+
+/// Unmap `region` and release it
+fn unmap_and_release(region: &Region) {
+    memory::with_global_paging(|mapper, frame_allocator| unsafe {
+        region.unmap(mapper, frame_allocator)
+    })
+    .unwrap();
+    assert!(vm::release(region));
+}
+
+/// Check that what the bootloader mapped is recorded
+#[test_case]
+fn bootloader_regions_are_recorded() {
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let physical_memory = vm::region_at(phys_mem_offset).expect("no physical memory region");
+    assert_eq!(physical_memory.kind(), RegionKind::PhysicalMemory);
+
+    // the kernel image
+    let kernel = vm::region_at(VirtAddr::from_ptr(&PHYS_MEM_OFFSET)).expect("no kernel region");
+    assert_eq!(kernel.kind(), RegionKind::Bootloader);
+}
+
+/// Check that the heap lives in its own region, aligned for huge pages
+#[test_case]
+fn heap_is_a_region() {
+    let heap_start = VirtAddr::new(allocator::heap_start() as u64);
+    let heap = vm::region_at(heap_start).expect("no heap region");
+    assert_eq!(heap.kind(), RegionKind::Heap);
+    assert_eq!(heap.start(), heap_start);
+    assert_eq!(heap.size(), HEAP_RESERVED_SIZE as u64);
+    assert!(heap_start.is_aligned(Size2MiB::SIZE));
+}
+
+/// Check that reserved regions are aligned, in the kernel half and disjoint from every other
+/// region
+#[test_case]
+fn reservations_dont_overlap() {
+    let requests = [
+        (1, 1),
+        (3 * Size4KiB::SIZE, Size4KiB::SIZE),
+        (Size4KiB::SIZE, Size2MiB::SIZE),
+        (5 * Size4KiB::SIZE + 1, 64 * Size4KiB::SIZE),
+        (Size2MiB::SIZE, Size2MiB::SIZE),
+    ];
+    let mut regions = [None; 5];
+    for (slot, (size, align)) in regions.iter_mut().zip(requests) {
+        let region =
+            vm::reserve(size, align, FLAGS, RegionKind::Other).expect("reservation failed");
+        assert!(region.start().is_aligned(align.max(Size4KiB::SIZE)));
+        assert!(region.size() >= size);
+        assert!(region.start().as_u64() >= KERNEL_SPACE_START);
+        assert!(region.end().as_u64() <= KERNEL_SPACE_END);
+        *slot = Some(region);
+    }
+
+    // the listing is sorted, so checking neighbours is enough
+    let mut previous: Option<Region> = None;
+    vm::for_each_region(|region| {
+        if let Some(previous) = previous {
+            assert!(
+                previous.end() <= region.start(),
+                "{:?} overlaps {:?}",
+                previous,
+                region
+            );
+        }
+        previous = Some(*region);
+    });
+
+    for region in regions.iter().flatten() {
+        assert!(vm::release(region));
+    }
+}
+
+/// Check that a reservation larger than the kernel half fails
+#[test_case]
+fn reserve_too_large() {
+    let size = KERNEL_SPACE_END - KERNEL_SPACE_START;
+    assert_eq!(vm::reserve(size, 1, FLAGS, RegionKind::Other), None);
+}
+
+/// Check that a region can be mapped, used, and unmapped giving its frames back, after which its
+/// range can be reserved again
+#[test_case]
+fn map_and_unmap() {
+    let region = vm::reserve(3 * Size4KiB::SIZE, 1, FLAGS, RegionKind::Other).unwrap();
+    let used = used_frames();
+    map(&region);
+    let used_mapped = used_frames();
+    assert!(used_mapped >= used + 3);
+
+    for offset in (0..region.size()).step_by(Size4KiB::SIZE as usize) {
+        let ptr: *mut u64 = (region.start() + offset).as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(offset);
+            assert_eq!(ptr.read_volatile(), offset);
+        }
+    }
+
+    unmap_and_release(&region);
+    assert!(!is_mapped(region.start()));
+    // the page tables stay
+    assert_eq!(used_frames(), used_mapped - 3);
+
+    let again = vm::reserve(3 * Size4KiB::SIZE, 1, FLAGS, RegionKind::Other).unwrap();
+    assert_eq!(again.start(), region.start());
+    assert!(vm::release(&again));
+}
+
+/// Check that unmapping a region mapped with huge pages gives the huge frames back
+#[test_case]
+fn unmap_huge_pages() {
+    let region = vm::reserve(2 * Size2MiB::SIZE, Size2MiB::SIZE, FLAGS, RegionKind::Other).unwrap();
+    map(&region);
+    let used_mapped = used_frames();
+
+    unmap_and_release(&region);
+    assert!(!is_mapped(region.start()));
+    assert!(!is_mapped(region.start() + Size2MiB::SIZE));
+    assert_eq!(used_frames(), used_mapped - 2 * 512);
+}
+
+/// Check that an MMIO region maps the given physical memory, and that unmapping it doesn't free
+/// the device's frames
+#[test_case]
+fn mmio_region() {
+    let vga = PhysAddr::new(0xb8000);
+    let region = vm::reserve(Size4KiB::SIZE, 1, FLAGS, RegionKind::Mmio).unwrap();
+    memory::with_global_paging(|mapper, frame_allocator| unsafe {
+        region.map_to_phys(vga, mapper, frame_allocator)
+    })
+    .unwrap()
+    .expect("mapping failed");
+
+    // the same memory is visible through the physical memory mapping
+    let phys_mem_offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+    let through_region: *mut u16 = region.start().as_mut_ptr();
+    let through_offset = (phys_mem_offset + vga.as_u64()) as *mut u16;
+    unsafe {
+        let old = through_offset.read_volatile();
+        through_region.write_volatile(0x0f41);
+        assert_eq!(through_offset.read_volatile(), 0x0f41);
+        through_region.write_volatile(old);
+    }
+
+    let used = used_frames();
+    unmap_and_release(&region);
+    assert!(!is_mapped(region.start()));
+    assert_eq!(used_frames(), used);
+}